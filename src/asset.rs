@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use crate::block::HashFn;
+use crate::wallet::WalletError;
+
+pub const MAX_ASSET_NAME_LEN: usize = 32;
+
+// Defines a new asset in the transaction carrying it. The whole supply must
+// go to that transaction's outputs, and the asset id is derived from its
+// first input, so the same asset can never be issued twice.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetIssuance {
+    name: String,
+    supply: i32,
+}
+
+impl AssetIssuance {
+    pub fn new(name: &str, supply: i32) -> Result<AssetIssuance, WalletError> {
+        let issuance = AssetIssuance {
+            name: String::from(name),
+            supply,
+        };
+        if !issuance.is_valid() {
+            return Err(WalletError::InvalidAsset(format!(
+                "an asset needs a name of 1 to {} characters and a positive supply",
+                MAX_ASSET_NAME_LEN
+            )));
+        }
+        Ok(issuance)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.name.is_empty() && self.name.len() <= MAX_ASSET_NAME_LEN && self.supply > 0
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn get_supply(&self) -> i32 {
+        self.supply
+    }
+}
+
+// the id of the asset issued by a transaction whose first input spends
+// output `vout` of `txid`
+pub fn asset_id(txid: &[u8], vout: usize, hash_fn: HashFn) -> Vec<u8> {
+    let mut data = txid.to_vec();
+    data.extend((vout as u64).to_be_bytes());
+    hash_fn(data.as_slice())
+}
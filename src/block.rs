@@ -1,7 +1,11 @@
+use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 
-use crate::{proof_of_work::ProofOfWork, transaction::Transaction};
+use crate::{
+    proof_of_work::{self, CancellationToken, MiningError, ProofOfWork},
+    transaction::Transaction,
+};
 
 //todo; move those public types to lib level
 pub type Hash = String;
@@ -64,6 +68,10 @@ impl Block {
         &self.timestamp
     }
 
+    pub fn get_nonce(&self) -> Nonce {
+        self.nonce
+    }
+
     pub fn deserialize(bytes: &[u8]) -> Result<Block, bincode::Error> {
         Ok(bincode::deserialize(bytes)?)
     }
@@ -84,6 +92,27 @@ impl Block {
         transactions: &[Transaction],
         height: usize,
     ) -> Block {
+        //todo:return erro below
+        Block::mine(
+            ts_provider,
+            hash_fn,
+            pre_block_hash,
+            transactions,
+            height,
+            proof_of_work::default_threads(),
+            &CancellationToken::new(),
+        )
+        .unwrap()
+    }
+    pub fn mine(
+        ts_provider: TimestampProvider,
+        hash_fn: HashFn,
+        pre_block_hash: Hash,
+        transactions: &[Transaction],
+        height: usize,
+        threads: usize,
+        token: &CancellationToken,
+    ) -> Result<Block, MiningError> {
         let mut block = Block::new_block(
             ts_provider,
             pre_block_hash,
@@ -93,11 +122,30 @@ impl Block {
             height,
         );
         let pow = ProofOfWork::new(&block);
-
-        //todo:return erro below
-        let (nonce, hash) = pow.run(hash_fn).unwrap();
-        block.nonce = nonce;
-        block.hash = hash;
+        let result = pow.run_parallel(hash_fn, ts_provider, threads, token)?;
+        block.nonce = result.nonce;
+        block.hash = result.hash;
+        block.timestamp = result.timestamp;
+        Ok(block)
+    }
+    // rebuilds a block from a template solved outside of this node
+    pub fn assemble(
+        hash_fn: HashFn,
+        pre_block_hash: Hash,
+        transactions: &[Transaction],
+        height: usize,
+        timestamp: Timestamp,
+        nonce: Nonce,
+    ) -> Block {
+        let mut block = Block {
+            timestamp,
+            pre_block_hash,
+            hash: String::new(),
+            transactions: transactions.into(),
+            nonce,
+            height,
+        };
+        block.hash = HEXLOWER.encode(&ProofOfWork::new(&block).compute_hash(hash_fn));
         block
     }
     pub fn generate_genesis_block(
@@ -116,7 +164,7 @@ impl Block {
         );
     }
 
-    pub(crate) fn get_height(&self) -> usize {
+    pub fn get_height(&self) -> usize {
         self.height
     }
     pub fn get_hash_bytes(&self) -> Vec<u8> {
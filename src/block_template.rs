@@ -0,0 +1,242 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use data_encoding::HEXLOWER;
+
+use crate::block::{Hash, HashFn};
+use crate::memory_pool::{ancestors_of, MemoryPoolEntry};
+use crate::transaction::{Transaction, SUBSIDY};
+
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+// room kept free for the coinbase transaction added by the miner
+const COINBASE_RESERVED_SIZE: usize = 1_000;
+// the coinbase pays the subsidy and all fees in one i32 output
+const MAX_TOTAL_FEES: i64 = (i32::MAX - SUBSIDY) as i64;
+
+pub struct BlockTemplate {
+    transactions: Vec<Transaction>,
+    total_fees: i64,
+    size: usize,
+}
+
+impl BlockTemplate {
+    pub fn get_transactions(&self) -> &[Transaction] {
+        self.transactions.as_slice()
+    }
+    // at most MAX_TOTAL_FEES, so the coinbase value always fits an i32
+    pub fn get_total_fees(&self) -> i64 {
+        self.total_fees
+    }
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+}
+
+// A package is a mempool transaction together with all of its not yet
+// selected in-pool ancestors. Packages are ranked by their combined fee rate,
+// so a high-fee child can pull a low-fee parent into the block.
+struct Package {
+    txids: HashSet<String>,
+    fee: i64,
+    size: usize,
+}
+
+impl Package {
+    fn compare_fee_rate(&self, other: &Package) -> Ordering {
+        let lhs = self.fee * other.size as i64;
+        let rhs = other.fee * self.size as i64;
+        lhs.cmp(&rhs)
+    }
+}
+
+pub struct BlockTemplateBuilder {
+    max_block_size: usize,
+}
+
+impl Default for BlockTemplateBuilder {
+    fn default() -> Self {
+        BlockTemplateBuilder::new(MAX_BLOCK_SIZE)
+    }
+}
+
+impl BlockTemplateBuilder {
+    pub fn new(max_block_size: usize) -> BlockTemplateBuilder {
+        BlockTemplateBuilder { max_block_size }
+    }
+
+    pub fn build(&self, pool: &HashMap<String, MemoryPoolEntry>) -> BlockTemplate {
+        let size_limit = self.max_block_size.saturating_sub(COINBASE_RESERVED_SIZE);
+        // ancestor sets don't change while the template is built, and a
+        // package only changes once one of its ancestors is selected
+        let ancestors: HashMap<&str, HashSet<String>> = pool
+            .keys()
+            .map(|txid_hex| (txid_hex.as_str(), ancestors_of(pool, txid_hex)))
+            .collect();
+        let mut packages: HashMap<&str, Package> = pool
+            .keys()
+            .map(|txid_hex| {
+                let package = Self::package_of(pool, txid_hex, &ancestors, &HashSet::new());
+                (txid_hex.as_str(), package)
+            })
+            .collect();
+        let mut selected: HashSet<String> = HashSet::new();
+        let mut skipped: HashSet<&str> = HashSet::new();
+        let mut transactions = vec![];
+        let mut total_fees: i64 = 0;
+        let mut size = 0;
+
+        loop {
+            let mut best: Option<&str> = None;
+            for (txid_hex, package) in &packages {
+                if skipped.contains(txid_hex) {
+                    continue;
+                }
+                if size + package.size > size_limit || total_fees + package.fee > MAX_TOTAL_FEES {
+                    skipped.insert(txid_hex);
+                    continue;
+                }
+                let better = match best {
+                    Some(current) => {
+                        package.compare_fee_rate(&packages[current]) == Ordering::Greater
+                    }
+                    None => true,
+                };
+                if better {
+                    best = Some(txid_hex);
+                }
+            }
+            let package = match best {
+                Some(txid_hex) => packages.remove(txid_hex).unwrap(),
+                None => break,
+            };
+
+            for txid_hex in Self::parents_first(&ancestors, &package.txids) {
+                let entry = pool.get(txid_hex.as_str()).unwrap();
+                transactions.push(entry.get_tx().clone());
+                packages.remove(txid_hex.as_str());
+                selected.insert(txid_hex);
+            }
+            total_fees += package.fee;
+            size += package.size;
+            // a package shrinks once some of its ancestors are in the block,
+            // so a skipped one may fit now
+            for (txid_hex, stale) in packages.iter_mut() {
+                if !ancestors[txid_hex].is_disjoint(&package.txids) {
+                    *stale = Self::package_of(pool, txid_hex, &ancestors, &selected);
+                    skipped.remove(txid_hex);
+                }
+            }
+        }
+
+        BlockTemplate {
+            transactions,
+            total_fees,
+            size,
+        }
+    }
+
+    fn package_of(
+        pool: &HashMap<String, MemoryPoolEntry>,
+        txid_hex: &str,
+        ancestors: &HashMap<&str, HashSet<String>>,
+        selected: &HashSet<String>,
+    ) -> Package {
+        let mut txids: HashSet<String> = ancestors[txid_hex]
+            .iter()
+            .filter(|ancestor| !selected.contains(*ancestor))
+            .cloned()
+            .collect();
+        txids.insert(txid_hex.to_string());
+
+        let mut fee = 0;
+        let mut size = 0;
+        for txid in &txids {
+            let entry = pool.get(txid.as_str()).unwrap();
+            fee += entry.get_fee() as i64;
+            size += entry.get_size();
+        }
+        Package { txids, fee, size }
+    }
+
+    // orders a package so that every transaction comes after the in-pool
+    // parents it spends from
+    fn parents_first(
+        ancestors: &HashMap<&str, HashSet<String>>,
+        txids: &HashSet<String>,
+    ) -> Vec<String> {
+        let mut ordered: Vec<String> = txids.iter().cloned().collect();
+        ordered.sort_by_key(|txid| {
+            let depth = ancestors[txid.as_str()]
+                .iter()
+                .filter(|ancestor| txids.contains(*ancestor))
+                .count();
+            (depth, txid.clone())
+        });
+        ordered
+    }
+}
+
+// Templates handed out to external miners, kept until the tip moves on so a
+// solved header can be matched back to its transactions.
+#[derive(Clone)]
+pub struct IssuedTemplate {
+    pre_block_hash: Hash,
+    height: usize,
+    transactions: Vec<Transaction>,
+}
+
+impl IssuedTemplate {
+    pub fn new(pre_block_hash: Hash, height: usize, transactions: Vec<Transaction>) -> Self {
+        IssuedTemplate {
+            pre_block_hash,
+            height,
+            transactions,
+        }
+    }
+    pub fn get_pre_block_hash(&self) -> &Hash {
+        &self.pre_block_hash
+    }
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+    pub fn get_transactions(&self) -> &[Transaction] {
+        self.transactions.as_slice()
+    }
+    pub fn id(&self, hash_fn: HashFn) -> String {
+        let mut data = self.pre_block_hash.as_bytes().to_vec();
+        for tx in &self.transactions {
+            data.extend(tx.get_id());
+        }
+        HEXLOWER.encode(&hash_fn(&data))
+    }
+}
+
+pub struct IssuedTemplates {
+    inner: RwLock<HashMap<String, IssuedTemplate>>,
+}
+
+impl Default for IssuedTemplates {
+    fn default() -> Self {
+        IssuedTemplates::new()
+    }
+}
+
+impl IssuedTemplates {
+    pub fn new() -> IssuedTemplates {
+        IssuedTemplates {
+            inner: RwLock::new(HashMap::new()),
+        }
+    }
+    pub fn add(&self, template_id: String, template: IssuedTemplate) {
+        let mut inner = self.inner.write().unwrap();
+        inner.retain(|_, issued| issued.pre_block_hash.eq(&template.pre_block_hash));
+        inner.insert(template_id, template);
+    }
+    pub fn get(&self, template_id: &str) -> Option<IssuedTemplate> {
+        self.inner.read().unwrap().get(template_id).cloned()
+    }
+}
@@ -1,7 +1,8 @@
 use core::panic;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env::current_dir,
+    fmt,
     sync::{Arc, RwLock},
 };
 
@@ -12,17 +13,74 @@ use sled::{
 };
 
 use crate::{
+    asset::AssetIssuance,
     block::{Block, Hash, HashFn, SignVerifyFn, TimestampProvider},
-    transaction::{TXOutput, Transaction},
+    proof_of_work::{self, CancellationToken, MiningError, ProofOfWork},
+    transaction::{Transaction, SUBSIDY},
+    utxo_set::{OutPoint, UTXOSet, UnspentOutput},
 };
 
-const DATA_FOLDER: &'static str = "Data";
+const DATA_FOLDER: &'static str = "data";
 const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
 const BLOCKS_TREE: &str = "blocks";
 
+#[derive(Debug)]
+pub enum BlockError {
+    StaleParent,
+    InvalidHeight,
+    InvalidProofOfWork,
+    InvalidCoinbase,
+    InvalidTransaction(String),
+    DoubleSpend(String),
+    LockedTransaction(String),
+    ImmatureCoinbase(String),
+    UnknownInputs(String),
+}
+
+impl BlockError {
+    // the offending transaction, for errors about a single one
+    pub fn get_txid(&self) -> Option<&str> {
+        match self {
+            BlockError::InvalidTransaction(txid)
+            | BlockError::DoubleSpend(txid)
+            | BlockError::LockedTransaction(txid)
+            | BlockError::ImmatureCoinbase(txid)
+            | BlockError::UnknownInputs(txid) => Some(txid.as_str()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::StaleParent => write!(f, "block does not extend the current tip"),
+            BlockError::InvalidHeight => write!(f, "block height does not follow the tip"),
+            BlockError::InvalidProofOfWork => write!(f, "block hash does not meet the target"),
+            BlockError::InvalidCoinbase => write!(f, "block has an invalid coinbase"),
+            BlockError::InvalidTransaction(txid) => write!(f, "transaction {} is invalid", txid),
+            BlockError::DoubleSpend(txid) => {
+                write!(f, "transaction {} spends an output twice", txid)
+            }
+            BlockError::LockedTransaction(txid) => {
+                write!(f, "transaction {} is still timelocked", txid)
+            }
+            BlockError::ImmatureCoinbase(txid) => {
+                write!(f, "transaction {} spends an immature coinbase", txid)
+            }
+            BlockError::UnknownInputs(txid) => {
+                write!(f, "transaction {} spends unknown outputs", txid)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
 #[derive(Clone)]
 pub struct Blockchain {
     tip_hash: Arc<RwLock<String>>, // hash of last block
+    tip_watchers: Arc<RwLock<Vec<CancellationToken>>>,
     db: Db,
 }
 
@@ -42,7 +100,8 @@ impl Blockchain {
 
             None => {
                 let coinbase_tx =
-                    Transaction::new_coinbase_tx(hash_fn, decoder, genesis_address).unwrap();
+                    Transaction::new_coinbase_tx(hash_fn, decoder, genesis_address, 0, 0, &[])
+                        .unwrap();
                 let block = Block::generate_genesis_block(ts_provider, hash_fn, &coinbase_tx);
                 Self::update_blocks_tree(&block_tree, &block);
                 String::from(block.get_hash())
@@ -51,6 +110,7 @@ impl Blockchain {
 
         Ok(Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
+            tip_watchers: Arc::new(RwLock::new(vec![])),
             db,
         })
     }
@@ -86,6 +146,19 @@ impl Blockchain {
     fn set_tip_hash(&self, new_tip_hash: &Hash) {
         let mut tip_hash = self.tip_hash.write().unwrap();
         *tip_hash = Hash::from(new_tip_hash);
+        let mut tip_watchers = self.tip_watchers.write().unwrap();
+        for token in tip_watchers.drain(..) {
+            token.cancel();
+        }
+    }
+    pub fn get_tip_hash_bytes(&self) -> Vec<u8> {
+        self.get_tip_hash().into_bytes()
+    }
+    // returns a token that is cancelled the next time the tip changes
+    pub fn watch_tip(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.tip_watchers.write().unwrap().push(token.clone());
+        token
     }
     pub fn iterator(&self) -> BlockchainIterator {
         BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
@@ -97,26 +170,74 @@ impl Blockchain {
         sign_verify_fn: SignVerifyFn,
         ts_provider: TimestampProvider,
     ) -> Block {
-        for transaction in transactions {
-            if (transaction.verify(self, hash_fn, sign_verify_fn)) == false {
-                panic!("ERROR: Invalid transaction")
+        let token = CancellationToken::new();
+        self.try_mine_block(
+            transactions,
+            hash_fn,
+            sign_verify_fn,
+            ts_provider,
+            proof_of_work::default_threads(),
+            &token,
+        )
+        .unwrap_or_else(|e| panic!("ERROR: {}", e))
+    }
+    // mines on the current tip; fails with MiningError::Rejected when a
+    // transaction can't go in the block, and with MiningError::Cancelled when `token`
+    // is cancelled or another block replaced the tip before a hash was found
+    pub fn try_mine_block(
+        &self,
+        transactions: &[Transaction],
+        hash_fn: HashFn,
+        sign_verify_fn: SignVerifyFn,
+        ts_provider: TimestampProvider,
+        threads: usize,
+        token: &CancellationToken,
+    ) -> Result<Block, MiningError> {
+        let best_height = self.get_best_height();
+        let mut spent = HashSet::new();
+        for (idx, transaction) in transactions.iter().enumerate() {
+            let txid_hex = HEXLOWER.encode(transaction.get_id());
+            let pending = &transactions[..idx];
+            if transaction.is_coinbase() {
+                // a coinbase built for an older tip is stale work
+                if transaction.get_coinbase_height() != Some(best_height + 1) {
+                    return Err(MiningError::Cancelled);
+                }
+                if !transaction.assets_balanced(self, pending, hash_fn)
+                    || !transaction.tokens_conserved(self, pending, hash_fn)
+                {
+                    return Err(MiningError::Rejected(BlockError::InvalidCoinbase));
+                }
+            } else {
+                for vin in transaction.get_vin() {
+                    if !spent.insert((vin.get_txid().to_vec(), vin.get_vout())) {
+                        return Err(MiningError::Rejected(BlockError::DoubleSpend(txid_hex)));
+                    }
+                }
+                self.check_transaction(transaction, pending, hash_fn, sign_verify_fn)
+                    .map_err(MiningError::Rejected)?;
             }
         }
-        let best_height = self.get_best_height();
-        let block = Block::new(
+        let tip_hash = self.get_tip_hash();
+        let token = token.or(&self.watch_tip());
+        let block = Block::mine(
             ts_provider,
             hash_fn,
-            self.get_tip_hash(),
+            tip_hash.clone(),
             transactions,
             best_height + 1,
-        );
-        let block_hash = block.get_hash();
+            threads,
+            &token,
+        )?;
 
+        if self.get_tip_hash() != tip_hash {
+            return Err(MiningError::Cancelled);
+        }
         let blocks_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
         Self::update_blocks_tree(&blocks_tree, &block);
-        self.set_tip_hash(block_hash);
+        self.set_tip_hash(block.get_hash());
 
-        block
+        Ok(block)
     }
     pub fn get_best_height(&self) -> usize {
         let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
@@ -129,6 +250,10 @@ impl Blockchain {
     }
 
     pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+        self.find_transaction_with_height(txid).map(|(tx, _)| tx)
+    }
+    // the transaction and the height of the block it was confirmed in
+    pub fn find_transaction_with_height(&self, txid: &[u8]) -> Option<(Transaction, usize)> {
         let mut iterator = self.iterator();
         loop {
             let option = iterator.next();
@@ -138,6 +263,71 @@ impl Blockchain {
             let block = option.unwrap();
             for transaction in block.get_transactions() {
                 if txid.eq(transaction.get_id()) {
+                    return Some((transaction.clone(), block.get_height()));
+                }
+            }
+        }
+        None
+    }
+    // the earliest block, and its transaction, with a data output carrying `data`
+    pub fn find_data(&self, data: &[u8]) -> Option<(Block, Transaction)> {
+        let mut found = None;
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next() {
+            let transaction = block.get_transactions().iter().find(|tx| {
+                tx.get_vout()
+                    .iter()
+                    .any(|out| out.get_script_pubkey().as_data_carrier() == Some(data))
+            });
+            if let Some(transaction) = transaction {
+                found = Some((block.clone(), transaction.clone()));
+            }
+        }
+        found
+    }
+    // the definition of `asset`, from the transaction that issued it
+    pub fn find_issuance(&self, asset: &[u8], hash_fn: HashFn) -> Option<AssetIssuance> {
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next() {
+            for transaction in block.get_transactions() {
+                if transaction.get_issued_asset(hash_fn).as_deref() == Some(asset) {
+                    return transaction.get_issuance().cloned();
+                }
+            }
+        }
+        None
+    }
+    // every transaction that moved `token_id`, with the height of its block,
+    // from the latest back to the mint
+    pub fn find_nft_history(&self, token_id: &[u8]) -> Vec<(Transaction, usize)> {
+        let mut history = vec![];
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next() {
+            for transaction in block.get_transactions().iter().rev() {
+                let carries = transaction.get_vout().iter().any(|out| {
+                    out.get_nft()
+                        .is_some_and(|token| token.get_token_id() == token_id)
+                });
+                if carries {
+                    history.push((transaction.clone(), block.get_height()));
+                }
+            }
+        }
+        history
+    }
+    // the confirmed transaction spending output `vout` of `txid`
+    pub fn find_spending_transaction(&self, txid: &[u8], vout: usize) -> Option<Transaction> {
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next() {
+            for transaction in block.get_transactions() {
+                if transaction.is_coinbase() {
+                    continue;
+                }
+                let spends = transaction
+                    .get_vin()
+                    .iter()
+                    .any(|vin| vin.get_txid() == txid && vin.get_vout() == vout);
+                if spends {
                     return Some(transaction.clone());
                 }
             }
@@ -167,8 +357,112 @@ impl Blockchain {
             Ok(())
         });
     }
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
+    // Checks a transaction for the next block, with `pending` the transactions
+    // ahead of it in that block, or in the memory pool. Returns its fee.
+    pub fn check_transaction(
+        &self,
+        tx: &Transaction,
+        pending: &[Transaction],
+        hash_fn: HashFn,
+        sign_verify_fn: SignVerifyFn,
+    ) -> Result<i32, BlockError> {
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        if tx.is_coinbase() || !tx.has_valid_id(hash_fn) {
+            return Err(BlockError::InvalidTransaction(txid_hex));
+        }
+        let input_value = tx
+            .input_value(self, pending)
+            .ok_or_else(|| BlockError::UnknownInputs(txid_hex.clone()))?;
+        // the outputs exist, but unless they come from `pending` they must
+        // still be unspent on the chain
+        let utxo_set = UTXOSet::new(self.clone());
+        let spent_on_chain = tx.get_vin().iter().any(|vin| {
+            let outpoint = OutPoint::new(vin.get_txid(), vin.get_vout());
+            !pending
+                .iter()
+                .any(|parent| parent.get_id() == vin.get_txid())
+                && utxo_set.get_unspent_output(&outpoint).is_none()
+        });
+        if spent_on_chain {
+            return Err(BlockError::DoubleSpend(txid_hex));
+        }
+        if !tx.locks_satisfied(self, pending) {
+            return Err(BlockError::LockedTransaction(txid_hex));
+        }
+        if !tx.coinbase_spends_mature(self, pending) {
+            return Err(BlockError::ImmatureCoinbase(txid_hex));
+        }
+        if !tx.output_values_valid()
+            || input_value < tx.output_value()
+            || !tx.assets_balanced(self, pending, hash_fn)
+            || !tx.tokens_conserved(self, pending, hash_fn)
+            || !tx.verify_with_pending(self, pending, hash_fn, sign_verify_fn)
+        {
+            return Err(BlockError::InvalidTransaction(txid_hex));
+        }
+        i32::try_from(input_value - tx.output_value())
+            .map_err(|_| BlockError::InvalidTransaction(txid_hex))
+    }
+    // full validation of a block that should extend the current tip
+    pub fn validate_block(
+        &self,
+        block: &Block,
+        hash_fn: HashFn,
+        sign_verify_fn: SignVerifyFn,
+    ) -> Result<(), BlockError> {
+        if block.get_pre_block_hash().ne(&self.get_tip_hash()) {
+            return Err(BlockError::StaleParent);
+        }
+        if block.get_height() != self.get_best_height() + 1 {
+            return Err(BlockError::InvalidHeight);
+        }
+        if !ProofOfWork::new(block).validate(hash_fn) {
+            return Err(BlockError::InvalidProofOfWork);
+        }
+        let transactions = block.get_transactions();
+        let coinbases: Vec<&Transaction> =
+            transactions.iter().filter(|tx| tx.is_coinbase()).collect();
+        if coinbases.len() != 1 || coinbases[0].get_coinbase_height() != Some(block.get_height()) {
+            return Err(BlockError::InvalidCoinbase);
+        }
+
+        let mut fees = 0;
+        let mut spent = HashSet::new();
+        for (idx, tx) in transactions.iter().enumerate() {
+            if tx.is_coinbase() {
+                continue;
+            }
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            for vin in tx.get_vin() {
+                if !spent.insert((vin.get_txid().to_vec(), vin.get_vout())) {
+                    return Err(BlockError::DoubleSpend(txid_hex));
+                }
+            }
+            let pending = &transactions[..idx];
+            fees += self.check_transaction(tx, pending, hash_fn, sign_verify_fn)? as i64;
+        }
+        if !coinbases[0].has_valid_id(hash_fn)
+            || !coinbases[0].output_values_valid()
+            || coinbases[0].output_value() > SUBSIDY as i64 + fees
+            || !coinbases[0].assets_balanced(self, &[], hash_fn)
+            || !coinbases[0].tokens_conserved(self, &[], hash_fn)
+        {
+            return Err(BlockError::InvalidCoinbase);
+        }
+        Ok(())
+    }
+    pub fn accept_block(
+        &self,
+        block: &Block,
+        hash_fn: HashFn,
+        sign_verify_fn: SignVerifyFn,
+    ) -> Result<(), BlockError> {
+        self.validate_block(block, hash_fn, sign_verify_fn)?;
+        self.add_block(block);
+        Ok(())
+    }
+    pub fn find_utxo(&self) -> HashMap<String, Vec<UnspentOutput>> {
+        let mut utxo: HashMap<String, Vec<UnspentOutput>> = HashMap::new();
         let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
 
         let mut iterator = self.iterator();
@@ -178,21 +472,15 @@ impl Blockchain {
                 break;
             }
             let block = option.unwrap();
-            'outer: for tx in block.get_transactions() {
+            for tx in block.get_transactions() {
                 let txid_hex = HEXLOWER.encode(tx.get_id());
-                for (idx, out) in tx.get_vout().iter().enumerate() {
+                for out in UnspentOutput::from_transaction(tx, block.get_height()) {
                     if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
-                        for spend_out_idx in outs {
-                            if idx.eq(spend_out_idx) {
-                                continue 'outer;
-                            }
+                        if outs.contains(&out.get_outpoint().get_vout()) {
+                            continue;
                         }
                     }
-                    if utxo.contains_key(txid_hex.as_str()) {
-                        utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
-                    }
+                    utxo.entry(txid_hex.clone()).or_default().push(out);
                 }
                 if tx.is_coinbase() {
                     continue;
@@ -221,8 +509,11 @@ impl Blockchain {
         }
         return None;
     }
+    pub fn exists() -> bool {
+        current_dir().unwrap().join(DATA_FOLDER).exists()
+    }
     pub fn new_blockchain() -> Blockchain {
-        let db = sled::open(current_dir().unwrap().join("data")).unwrap();
+        let db = sled::open(current_dir().unwrap().join(DATA_FOLDER)).unwrap();
         let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
         let tip_bytes = blocks_tree
             .get(TIP_BLOCK_HASH_KEY)
@@ -231,6 +522,7 @@ impl Blockchain {
         let tip_hash = String::from_utf8(tip_bytes.to_vec()).unwrap();
         Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
+            tip_watchers: Arc::new(RwLock::new(vec![])),
             db,
         }
     }
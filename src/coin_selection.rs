@@ -0,0 +1,555 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::fee_estimator::fee_for_size;
+use crate::transaction::Transaction;
+use crate::utxo_set::{OutPoint, UnspentOutput};
+use crate::wallet::WalletError;
+
+// branch and bound gives up after visiting this many nodes
+const BNB_MAX_TRIES: usize = 100_000;
+const KNAPSACK_ITERATIONS: usize = 1_000;
+
+pub type CoinSelectionFn = fn(&[UnspentOutput], &SelectionParams) -> Option<Selection>;
+
+// What a transaction has to pay for: the recipients' amount plus a fee that
+// grows with every input, either from a fee rate or fixed by the user.
+pub struct SelectionParams {
+    amount: i32,
+    // recipient outputs, without change
+    num_outputs: usize,
+    // coins per 1000 bytes
+    fee_rate: i32,
+    fixed_fee: Option<i32>,
+}
+
+impl SelectionParams {
+    pub fn new(
+        amount: i32,
+        num_outputs: usize,
+        fee_rate: i32,
+        fixed_fee: Option<i32>,
+    ) -> SelectionParams {
+        SelectionParams {
+            amount,
+            num_outputs,
+            fee_rate,
+            fixed_fee,
+        }
+    }
+
+    fn fee(&self, num_inputs: usize, with_change: bool) -> i32 {
+        if let Some(fee) = self.fixed_fee {
+            return fee;
+        }
+        let num_outputs = self.num_outputs + with_change as usize;
+        fee_for_size(
+            self.fee_rate,
+            Transaction::estimate_size(num_inputs, num_outputs),
+        )
+    }
+
+    // fee paid for spending one more input
+    fn input_cost(&self) -> i32 {
+        self.fee(1, false) - self.fee(0, false)
+    }
+
+    // creating a change output now and spending it later
+    fn cost_of_change(&self) -> i32 {
+        self.fee(0, true) - self.fee(0, false) + self.input_cost()
+    }
+
+    fn effective_value(&self, utxo: &UnspentOutput) -> i32 {
+        utxo.get_value() - self.input_cost()
+    }
+
+    // value the effective values of the inputs must cover without change;
+    // totals are i64 so summing i32 values can't overflow them
+    fn target(&self) -> i64 {
+        self.amount as i64 + self.fee(0, false) as i64
+    }
+
+    // Turns a set of inputs into a selection, None if they do not cover the
+    // amount and fee or the change doesn't fit an output. Excess too small to
+    // be worth a change output is left to the fee.
+    pub fn finish(&self, inputs: Vec<UnspentOutput>) -> Option<Selection> {
+        let total: i64 = inputs.iter().map(|utxo| utxo.get_value() as i64).sum();
+        let fee = self.fee(inputs.len(), false);
+        let excess = total - self.amount as i64 - fee as i64;
+        if excess < 0 {
+            return None;
+        }
+        if excess <= self.cost_of_change() as i64 {
+            return Some(Selection {
+                inputs,
+                fee: i32::try_from(fee as i64 + excess).ok()?,
+                change: 0,
+            });
+        }
+        let fee = self.fee(inputs.len(), true);
+        Some(Selection {
+            change: i32::try_from(total - self.amount as i64 - fee as i64).ok()?,
+            inputs,
+            fee,
+        })
+    }
+}
+
+pub struct Selection {
+    inputs: Vec<UnspentOutput>,
+    fee: i32,
+    change: i32,
+}
+
+impl Selection {
+    pub fn get_inputs(&self) -> &[UnspentOutput] {
+        self.inputs.as_slice()
+    }
+    pub fn get_fee(&self) -> i32 {
+        self.fee
+    }
+    pub fn get_change(&self) -> i32 {
+        self.change
+    }
+}
+
+fn accumulate(sorted: Vec<UnspentOutput>, params: &SelectionParams) -> Option<Selection> {
+    let mut inputs = vec![];
+    for utxo in sorted {
+        inputs.push(utxo);
+        if let Some(selection) = params.finish(inputs.clone()) {
+            return Some(selection);
+        }
+    }
+    None
+}
+
+pub fn select_largest_first(
+    utxos: &[UnspentOutput],
+    params: &SelectionParams,
+) -> Option<Selection> {
+    let mut sorted = utxos.to_vec();
+    sorted.sort_by_key(|utxo| std::cmp::Reverse(utxo.get_value()));
+    accumulate(sorted, params)
+}
+
+pub fn select_oldest_first(utxos: &[UnspentOutput], params: &SelectionParams) -> Option<Selection> {
+    let mut sorted = utxos.to_vec();
+    sorted.sort_by_key(|utxo| utxo.get_height());
+    accumulate(sorted, params)
+}
+
+// Depth first search for a set of inputs that pays the target without a
+// change output, preferring the one that wastes the least to the fee.
+pub fn select_branch_and_bound(
+    utxos: &[UnspentOutput],
+    params: &SelectionParams,
+) -> Option<Selection> {
+    let mut candidates: Vec<(i32, &UnspentOutput)> = utxos
+        .iter()
+        .map(|utxo| (params.effective_value(utxo), utxo))
+        .filter(|(value, _)| *value > 0)
+        .collect();
+    candidates.sort_by_key(|(value, _)| std::cmp::Reverse(*value));
+    let values: Vec<i32> = candidates.iter().map(|(value, _)| *value).collect();
+
+    let mut search = BranchAndBound {
+        values: values.as_slice(),
+        target: params.target(),
+        upper_bound: params.target() + params.cost_of_change() as i64,
+        tries: 0,
+        selected: vec![],
+        best: None,
+    };
+    search.run(values.iter().map(|value| *value as i64).sum());
+
+    let (_, best) = search.best?;
+    let inputs = best.iter().map(|i| candidates[*i].1.clone()).collect();
+    params.finish(inputs)
+}
+
+struct BranchAndBound<'a> {
+    values: &'a [i32],
+    target: i64,
+    upper_bound: i64,
+    tries: usize,
+    selected: Vec<usize>,
+    // excess over the target and the inputs that produced it
+    best: Option<(i64, Vec<usize>)>,
+}
+
+// A node of the search tree: the decision to include or skip `values[index - 1]`
+// taken on top of the first `depth` selected inputs.
+struct Node {
+    index: usize,
+    sum: i64,
+    remaining: i64,
+    depth: usize,
+    include: Option<usize>,
+}
+
+impl BranchAndBound<'_> {
+    // walks the tree with an explicit stack, so the number of candidates is
+    // not limited by the thread's stack
+    fn run(&mut self, remaining: i64) {
+        let mut stack = vec![Node {
+            index: 0,
+            sum: 0,
+            remaining,
+            depth: 0,
+            include: None,
+        }];
+        while let Some(node) = stack.pop() {
+            self.selected.truncate(node.depth);
+            self.selected.extend(node.include);
+            self.tries += 1;
+            if self.tries > BNB_MAX_TRIES || matches!(self.best, Some((0, _))) {
+                return;
+            }
+            if node.sum + node.remaining < self.target || node.sum > self.upper_bound {
+                continue;
+            }
+            if node.sum >= self.target {
+                let excess = node.sum - self.target;
+                if self.best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                    self.best = Some((excess, self.selected.clone()));
+                }
+                continue;
+            }
+            if node.index == self.values.len() {
+                continue;
+            }
+            let value = self.values[node.index] as i64;
+            // pushed last so including the input is tried first
+            stack.push(Node {
+                index: node.index + 1,
+                sum: node.sum,
+                remaining: node.remaining - value,
+                depth: self.selected.len(),
+                include: None,
+            });
+            stack.push(Node {
+                index: node.index + 1,
+                sum: node.sum + value,
+                remaining: node.remaining - value,
+                depth: self.selected.len(),
+                include: Some(node.index),
+            });
+        }
+    }
+}
+
+// Randomised subset sum in the style of the original Bitcoin Core selector,
+// used when no changeless solution exists.
+pub fn select_knapsack(utxos: &[UnspentOutput], params: &SelectionParams) -> Option<Selection> {
+    let target = params.target();
+    // with change the inputs must also pay for the change output
+    let change_target = target + params.cost_of_change() as i64 + 1;
+
+    let mut smaller: Vec<(i32, &UnspentOutput)> = vec![];
+    let mut lowest_larger: Option<(i32, &UnspentOutput)> = None;
+    for utxo in utxos {
+        let value = params.effective_value(utxo);
+        if value <= 0 {
+            continue;
+        }
+        if value as i64 == target {
+            return params.finish(vec![utxo.clone()]);
+        }
+        if (value as i64) < change_target {
+            smaller.push((value, utxo));
+        } else if lowest_larger.is_none_or(|(lowest, _)| value < lowest) {
+            lowest_larger = Some((value, utxo));
+        }
+    }
+
+    let smaller_total: i64 = smaller.iter().map(|(value, _)| *value as i64).sum();
+    if smaller_total == target {
+        return params.finish(smaller.iter().map(|(_, utxo)| (*utxo).clone()).collect());
+    }
+    if smaller_total < change_target {
+        return lowest_larger.and_then(|(_, utxo)| params.finish(vec![utxo.clone()]));
+    }
+
+    smaller.sort_by_key(|(value, _)| std::cmp::Reverse(*value));
+    let values: Vec<i32> = smaller.iter().map(|(value, _)| *value).collect();
+    let (best_total, included) = approximate_best_subset(values.as_slice(), change_target);
+    if let Some((value, utxo)) = lowest_larger {
+        if value as i64 <= best_total {
+            return params.finish(vec![utxo.clone()]);
+        }
+    }
+    let inputs = smaller
+        .iter()
+        .zip(included)
+        .filter(|(_, included)| *included)
+        .map(|((_, utxo), _)| (*utxo).clone())
+        .collect();
+    params.finish(inputs)
+}
+
+fn approximate_best_subset(values: &[i32], target: i64) -> (i64, Vec<bool>) {
+    let mut best = vec![true; values.len()];
+    let mut best_total: i64 = values.iter().map(|value| *value as i64).sum();
+    for _ in 0..KNAPSACK_ITERATIONS {
+        if best_total == target {
+            break;
+        }
+        let random = crate::utils::random_bytes(values.len());
+        let mut included = vec![false; values.len()];
+        let mut total: i64 = 0;
+        let mut reached = false;
+        // first pass picks inputs at random, the second fills up with the rest
+        for pass in 0..2 {
+            if reached {
+                break;
+            }
+            for i in 0..values.len() {
+                let pick = if pass == 0 {
+                    random[i] & 1 == 1
+                } else {
+                    !included[i]
+                };
+                if !pick {
+                    continue;
+                }
+                total += values[i] as i64;
+                included[i] = true;
+                if total >= target {
+                    reached = true;
+                    if total < best_total {
+                        best_total = total;
+                        best = included.clone();
+                    }
+                    total -= values[i] as i64;
+                    included[i] = false;
+                }
+            }
+        }
+    }
+    (best_total, best)
+}
+
+// branch and bound first, knapsack when a change output is unavoidable
+pub fn select_auto(utxos: &[UnspentOutput], params: &SelectionParams) -> Option<Selection> {
+    select_branch_and_bound(utxos, params).or_else(|| select_knapsack(utxos, params))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSelectionStrategy {
+    #[default]
+    Auto,
+    BranchAndBound,
+    LargestFirst,
+    OldestFirst,
+    Knapsack,
+}
+
+impl CoinSelectionStrategy {
+    pub fn get_selector(&self) -> CoinSelectionFn {
+        match self {
+            CoinSelectionStrategy::Auto => select_auto,
+            CoinSelectionStrategy::BranchAndBound => select_branch_and_bound,
+            CoinSelectionStrategy::LargestFirst => select_largest_first,
+            CoinSelectionStrategy::OldestFirst => select_oldest_first,
+            CoinSelectionStrategy::Knapsack => select_knapsack,
+        }
+    }
+}
+
+impl FromStr for CoinSelectionStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(CoinSelectionStrategy::Auto),
+            "bnb" => Ok(CoinSelectionStrategy::BranchAndBound),
+            "largest" => Ok(CoinSelectionStrategy::LargestFirst),
+            "oldest" => Ok(CoinSelectionStrategy::OldestFirst),
+            "knapsack" => Ok(CoinSelectionStrategy::Knapsack),
+            _ => Err(format!(
+                "unknown coin selection strategy {}, expected auto, bnb, largest, oldest or knapsack",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CoinSelectionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CoinSelectionStrategy::Auto => "auto",
+            CoinSelectionStrategy::BranchAndBound => "bnb",
+            CoinSelectionStrategy::LargestFirst => "largest",
+            CoinSelectionStrategy::OldestFirst => "oldest",
+            CoinSelectionStrategy::Knapsack => "knapsack",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// How the wallet picks inputs: a strategy over all spendable outputs, or
+// exactly the outpoints the user listed.
+#[derive(Clone, Default)]
+pub struct CoinControl {
+    strategy: CoinSelectionStrategy,
+    inputs: Vec<OutPoint>,
+}
+
+impl CoinControl {
+    pub fn new(strategy: CoinSelectionStrategy, inputs: Vec<OutPoint>) -> CoinControl {
+        CoinControl { strategy, inputs }
+    }
+
+    // `owned` are all unspent outputs of the sender, frozen ones included
+    pub fn select(
+        &self,
+        owned: &[UnspentOutput],
+        is_frozen: impl Fn(&OutPoint) -> bool,
+        params: &SelectionParams,
+    ) -> Result<Selection, WalletError> {
+        if !self.inputs.is_empty() {
+            let mut inputs = vec![];
+            for outpoint in &self.inputs {
+                if is_frozen(outpoint) {
+                    return Err(WalletError::FrozenOutput(outpoint.to_string()));
+                }
+                let utxo = owned
+                    .iter()
+                    .find(|utxo| utxo.get_outpoint().eq(outpoint))
+                    .ok_or_else(|| WalletError::UnknownOutput(outpoint.to_string()))?;
+                if !inputs
+                    .iter()
+                    .any(|input: &UnspentOutput| input.get_outpoint().eq(outpoint))
+                {
+                    inputs.push(utxo.clone());
+                }
+            }
+            return params.finish(inputs).ok_or(WalletError::InsufficientFunds);
+        }
+
+        let spendable: Vec<UnspentOutput> = owned
+            .iter()
+            .filter(|utxo| !is_frozen(&utxo.get_outpoint()))
+            .cloned()
+            .collect();
+        if let Some(selection) = self.strategy.get_selector()(spendable.as_slice(), params) {
+            return Ok(selection);
+        }
+        if params.finish(spendable).is_none() {
+            return Err(WalletError::InsufficientFunds);
+        }
+        Err(WalletError::NoCoinSelection(self.strategy.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::SUBSIDY;
+    use crate::utils::sha256_digest;
+
+    // an output of `value` coins; `height` keeps the txids apart
+    fn utxo(value: i32, height: usize) -> UnspentOutput {
+        let tx = Transaction::new_coinbase_tx(
+            sha256_digest,
+            |_| vec![0; 25],
+            "",
+            value - SUBSIDY,
+            height,
+            &[],
+        )
+        .unwrap();
+        UnspentOutput::from_transaction(&tx, height).remove(0)
+    }
+
+    fn utxos(values: &[i32]) -> Vec<UnspentOutput> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(height, value)| utxo(*value, height))
+            .collect()
+    }
+
+    fn total(selection: &Selection) -> i32 {
+        selection
+            .get_inputs()
+            .iter()
+            .map(|utxo| utxo.get_value())
+            .sum()
+    }
+
+    #[test]
+    fn branch_and_bound_finds_exact_match() {
+        let params = SelectionParams::new(9, 1, 0, Some(1));
+        let selection = select_branch_and_bound(&utxos(&[7, 5, 3, 2]), &params).unwrap();
+        assert_eq!(total(&selection), 10);
+        assert_eq!(selection.get_fee(), 1);
+        assert_eq!(selection.get_change(), 0);
+    }
+
+    #[test]
+    fn branch_and_bound_needs_changeless_solution() {
+        let params = SelectionParams::new(10, 1, 0, Some(1));
+        assert!(select_branch_and_bound(&utxos(&[7, 5]), &params).is_none());
+    }
+
+    #[test]
+    fn branch_and_bound_handles_many_inputs() {
+        let params = SelectionParams::new(49_999, 1, 0, Some(1));
+        let selection = select_branch_and_bound(&utxos(&[1; 50_000]), &params).unwrap();
+        assert_eq!(selection.get_inputs().len(), 50_000);
+        assert_eq!(selection.get_change(), 0);
+    }
+
+    #[test]
+    fn knapsack_pays_change() {
+        let params = SelectionParams::new(4, 1, 0, Some(1));
+        let selection = select_knapsack(&utxos(&[1, 2, 3, 20]), &params).unwrap();
+        assert_eq!(total(&selection), 6);
+        assert_eq!(selection.get_change(), 1);
+
+        let selection = select_knapsack(&utxos(&[1, 2, 20]), &params).unwrap();
+        assert_eq!(total(&selection), 20);
+        assert_eq!(selection.get_change(), 15);
+    }
+
+    #[test]
+    fn knapsack_prefers_exact_match() {
+        let params = SelectionParams::new(4, 1, 0, Some(1));
+        let selection = select_knapsack(&utxos(&[20, 5, 3]), &params).unwrap();
+        assert_eq!(total(&selection), 5);
+        assert_eq!(selection.get_change(), 0);
+    }
+
+    #[test]
+    fn finish_leaves_dust_to_fee() {
+        let params = SelectionParams::new(100, 1, 1000, None);
+        let fee = params.fee(1, false);
+        let dust = params.cost_of_change();
+        let selection = params.finish(vec![utxo(100 + fee + dust, 0)]).unwrap();
+        assert_eq!(selection.get_change(), 0);
+        assert_eq!(selection.get_fee(), fee + dust);
+
+        let selection = params.finish(vec![utxo(100 + fee + dust + 1, 0)]).unwrap();
+        assert_eq!(selection.get_fee(), params.fee(1, true));
+        assert_eq!(selection.get_change(), fee + dust + 1 - params.fee(1, true));
+    }
+
+    #[test]
+    fn finish_rejects_insufficient_funds() {
+        let params = SelectionParams::new(100, 1, 1000, None);
+        let fee = params.fee(1, false);
+        assert!(params.finish(vec![utxo(100 + fee - 1, 0)]).is_none());
+        assert!(params.finish(vec![]).is_none());
+    }
+
+    #[test]
+    fn finish_does_not_overflow() {
+        let params = SelectionParams::new(1, 1, 0, Some(1));
+        let inputs = utxos(&[i32::MAX, i32::MAX]);
+        assert!(params.finish(inputs.clone()).is_none());
+        let selection = params.finish(inputs[..1].to_vec()).unwrap();
+        assert_eq!(selection.get_change(), i32::MAX - 2);
+    }
+}
@@ -6,9 +6,16 @@ use std::sync::RwLock;
 pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(|| Config::new());
 
 static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
+// blocks a coinbase output must be buried under before it can be spent. This
+// is a consensus rule: COINBASE_MATURITY only overrides it for test networks,
+// and every node of a network must run with the same value or they fork.
+const DEFAULT_COINBASE_MATURITY: usize = 100;
 
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+const COINBASE_MATURITY_KEY: &str = "COINBASE_MATURITY";
+// token printed by walletpassphrase, needed to use its wallet session
+const WALLET_SESSION_KEY: &str = "WALLET_SESSION";
 
 pub struct Config {
     inner: RwLock<HashMap<String, String>>,
@@ -20,8 +27,16 @@ impl Config {
         if let Ok(addr) = env::var("NODE_ADDRESS") {
             node_addr = addr;
         }
+        let mut coinbase_maturity = DEFAULT_COINBASE_MATURITY.to_string();
+        if let Ok(maturity) = env::var(COINBASE_MATURITY_KEY) {
+            coinbase_maturity = maturity;
+        }
         let mut map = HashMap::new();
         map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
+        map.insert(String::from(COINBASE_MATURITY_KEY), coinbase_maturity);
+        if let Ok(token) = env::var(WALLET_SESSION_KEY) {
+            map.insert(String::from(WALLET_SESSION_KEY), token);
+        }
 
         Config {
             inner: RwLock::new(map),
@@ -33,6 +48,19 @@ impl Config {
         inner.get(NODE_ADDRESS_KEY).unwrap().clone()
     }
 
+    pub fn get_coinbase_maturity(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        inner
+            .get(COINBASE_MATURITY_KEY)
+            .and_then(|maturity| maturity.parse().ok())
+            .expect("COINBASE_MATURITY must be a number of blocks")
+    }
+
+    pub fn get_wallet_session(&self) -> Option<String> {
+        let inner = self.inner.read().unwrap();
+        inner.get(WALLET_SESSION_KEY).cloned()
+    }
+
     pub fn set_mining_addr(&self, addr: String) {
         let mut inner = self.inner.write().unwrap();
         let _ = inner.insert(String::from(MINING_ADDRESS_KEY), addr);
@@ -0,0 +1,181 @@
+use data_encoding::HEXLOWER;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{block::Block, blockchain::Blockchain};
+
+const FEE_TRACKED_TREE: &str = "fee_tracked";
+const FEE_BUCKETS_TREE: &str = "fee_buckets";
+
+// fee rates are expressed in coins per 1000 bytes of serialized transaction
+pub const FEE_RATE_BUCKETS: [i32; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+pub const MIN_FEE_RATE: i32 = 1;
+pub const DEFAULT_CONFIRM_TARGET: usize = 2;
+pub const MAX_CONFIRM_TARGET: usize = 25;
+const SUCCESS_THRESHOLD: f64 = 0.85;
+const MIN_SAMPLES: f64 = 3.0;
+// weight kept by older observations every time a block is processed
+const DECAY: f64 = 0.998;
+
+#[derive(Serialize, Deserialize)]
+struct TrackedTx {
+    bucket: usize,
+    entry_height: usize,
+    entry_time: i64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct BucketStats {
+    // confirmed[i] = weight of transactions mined i + 1 blocks after arrival
+    confirmed: Vec<f64>,
+    total: f64,
+}
+
+impl BucketStats {
+    fn new() -> BucketStats {
+        BucketStats {
+            confirmed: vec![0.0; MAX_CONFIRM_TARGET],
+            total: 0.0,
+        }
+    }
+
+    fn success_rate(&self, target_blocks: usize) -> f64 {
+        let confirmed: f64 = self.confirmed[..target_blocks].iter().sum();
+        confirmed / self.total
+    }
+}
+
+pub fn fee_rate(fee: i32, size: usize) -> i32 {
+    if size == 0 {
+        return 0;
+    }
+    (fee as i64 * 1000 / size as i64) as i32
+}
+
+pub fn fee_for_size(fee_rate: i32, size: usize) -> i32 {
+    ((fee_rate as i64 * size as i64 + 999) / 1000) as i32
+}
+
+pub struct FeeEstimator {
+    blockchain: Blockchain,
+}
+
+impl FeeEstimator {
+    pub fn new(blockchain: Blockchain) -> FeeEstimator {
+        FeeEstimator { blockchain }
+    }
+
+    fn bucket_for(fee_rate: i32) -> usize {
+        FEE_RATE_BUCKETS
+            .iter()
+            .rposition(|bucket| fee_rate >= *bucket)
+            .unwrap_or(0)
+    }
+
+    fn load_bucket(&self, bucket: usize) -> BucketStats {
+        let db = self.blockchain.get_db();
+        let buckets_tree = db.open_tree(FEE_BUCKETS_TREE).unwrap();
+        match buckets_tree.get(bucket.to_be_bytes()).unwrap() {
+            Some(bytes) => {
+                bincode::deserialize(bytes.as_ref()).expect("unable to deserialize BucketStats")
+            }
+            None => BucketStats::new(),
+        }
+    }
+
+    fn save_bucket(&self, bucket: usize, stats: &BucketStats) {
+        let db = self.blockchain.get_db();
+        let buckets_tree = db.open_tree(FEE_BUCKETS_TREE).unwrap();
+        let bytes = bincode::serialize(stats).expect("unable to serialize BucketStats");
+        let _ = buckets_tree.insert(bucket.to_be_bytes(), bytes).unwrap();
+    }
+
+    // called when a transaction enters the memory pool
+    pub fn process_transaction(&self, txid: &[u8], fee: i32, size: usize, entry_time: i64) {
+        let db = self.blockchain.get_db();
+        let tracked_tree = db.open_tree(FEE_TRACKED_TREE).unwrap();
+        if tracked_tree.contains_key(txid).unwrap() {
+            return;
+        }
+        let bucket = Self::bucket_for(fee_rate(fee, size));
+        let tracked = TrackedTx {
+            bucket,
+            entry_height: self.blockchain.get_best_height(),
+            entry_time,
+        };
+        let bytes = bincode::serialize(&tracked).expect("unable to serialize TrackedTx");
+        let _ = tracked_tree.insert(txid, bytes).unwrap();
+
+        let mut stats = self.load_bucket(bucket);
+        stats.total += 1.0;
+        self.save_bucket(bucket, &stats);
+    }
+
+    // called when a block is connected; records how many blocks every tracked
+    // transaction in it waited for confirmation
+    pub fn process_block(&self, block: &Block) {
+        let db = self.blockchain.get_db();
+        let tracked_tree = db.open_tree(FEE_TRACKED_TREE).unwrap();
+
+        let mut buckets: Vec<BucketStats> = (0..FEE_RATE_BUCKETS.len())
+            .map(|bucket| self.load_bucket(bucket))
+            .collect();
+        for stats in buckets.iter_mut() {
+            stats.total *= DECAY;
+            stats
+                .confirmed
+                .iter_mut()
+                .for_each(|weight| *weight *= DECAY);
+        }
+
+        for tx in block.get_transactions() {
+            let tracked_bytes = match tracked_tree.remove(tx.get_id()).unwrap() {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let tracked: TrackedTx = bincode::deserialize(tracked_bytes.as_ref())
+                .expect("unable to deserialize TrackedTx");
+            let blocks = block
+                .get_height()
+                .saturating_sub(tracked.entry_height)
+                .clamp(1, MAX_CONFIRM_TARGET);
+            buckets[tracked.bucket].confirmed[blocks - 1] += 1.0;
+            info!(
+                "Transaction {} confirmed after {} blocks ({} ms in memory pool)",
+                HEXLOWER.encode(tx.get_id()),
+                blocks,
+                crate::utils::current_timestamp() - tracked.entry_time
+            );
+        }
+
+        // transactions still unconfirmed past the longest target stay counted
+        // in their bucket's total as failures, but are no longer tracked
+        for item in tracked_tree.iter() {
+            let (txid, tracked_bytes) = item.unwrap();
+            let tracked: TrackedTx = bincode::deserialize(tracked_bytes.as_ref())
+                .expect("unable to deserialize TrackedTx");
+            if block.get_height() > tracked.entry_height + MAX_CONFIRM_TARGET {
+                let _ = tracked_tree.remove(txid).unwrap();
+            }
+        }
+
+        for (bucket, stats) in buckets.iter().enumerate() {
+            self.save_bucket(bucket, stats);
+        }
+    }
+
+    // lowest fee rate that historically confirmed within `target_blocks`
+    pub fn estimate_fee(&self, target_blocks: usize) -> Option<i32> {
+        let target_blocks = target_blocks.clamp(1, MAX_CONFIRM_TARGET);
+        for (bucket, fee_rate) in FEE_RATE_BUCKETS.iter().enumerate() {
+            let stats = self.load_bucket(bucket);
+            if stats.total < MIN_SAMPLES {
+                continue;
+            }
+            if stats.success_rate(target_blocks) >= SUCCESS_THRESHOLD {
+                return Some(*fee_rate);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,160 @@
+// BIP32 style hierarchical key derivation on the NIST P-256 curve, following
+// the SLIP-0010 variant of the algorithm for curves other than secp256k1.
+use hmac::{Hmac, Mac};
+use p256::elliptic_curve::ops::Reduce;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::pkcs8::EncodePrivateKey;
+use p256::{FieldBytes, NonZeroScalar, Scalar, SecretKey, U256};
+use sha2::Sha512;
+
+const MASTER_KEY_SALT: &[u8] = b"Nist256p1 seed";
+pub const HARDENED_OFFSET: u32 = 0x8000_0000;
+
+type HmacSha512 = Hmac<Sha512>;
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = HmacSha512::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+// Parsed form of a path such as m/44'/1'/0'/0/5
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DerivationPath {
+    indexes: Vec<u32>,
+}
+
+impl DerivationPath {
+    pub fn parse(path: &str) -> Option<DerivationPath> {
+        let mut parts = path.trim().split('/');
+        if parts.next()? != "m" {
+            return None;
+        }
+        let mut indexes = vec![];
+        for part in parts {
+            let (number, hardened) = match part.strip_suffix('\'').or(part.strip_suffix('h')) {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            let index: u32 = number.parse().ok()?;
+            if index >= HARDENED_OFFSET {
+                return None;
+            }
+            indexes.push(if hardened {
+                index + HARDENED_OFFSET
+            } else {
+                index
+            });
+        }
+        Some(DerivationPath { indexes })
+    }
+
+    pub fn child(&self, index: u32) -> DerivationPath {
+        let mut indexes = self.indexes.clone();
+        indexes.push(index);
+        DerivationPath { indexes }
+    }
+
+    pub fn get_indexes(&self) -> &[u32] {
+        self.indexes.as_slice()
+    }
+}
+
+impl std::fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "m")?;
+        for index in &self.indexes {
+            if *index >= HARDENED_OFFSET {
+                write!(f, "/{}'", index - HARDENED_OFFSET)?;
+            } else {
+                write!(f, "/{}", index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct ExtendedPrivateKey {
+    secret_key: SecretKey,
+    chain_code: [u8; 32],
+}
+
+impl ExtendedPrivateKey {
+    pub fn from_seed(seed: &[u8]) -> ExtendedPrivateKey {
+        let mut data = seed.to_vec();
+        loop {
+            let i = hmac_sha512(MASTER_KEY_SALT, &data);
+            if let Some(key) = Self::from_parts(&i[..32], &i[32..]) {
+                return key;
+            }
+            data = i.to_vec();
+        }
+    }
+
+    fn from_parts(secret: &[u8], chain_code: &[u8]) -> Option<ExtendedPrivateKey> {
+        let secret_key = SecretKey::from_slice(secret).ok()?;
+        Some(ExtendedPrivateKey {
+            secret_key,
+            chain_code: chain_code.try_into().ok()?,
+        })
+    }
+
+    // uncompressed SEC1 encoding, the same form ring reports for its key pairs
+    pub fn public_key(&self) -> Vec<u8> {
+        let point = self.secret_key.public_key().to_encoded_point(false);
+        point.as_bytes().to_vec()
+    }
+
+    fn compressed_public_key(&self) -> Vec<u8> {
+        let point = self.secret_key.public_key().to_encoded_point(true);
+        point.as_bytes().to_vec()
+    }
+
+    pub fn to_pkcs8(&self) -> Vec<u8> {
+        let document = self
+            .secret_key
+            .to_pkcs8_der()
+            .expect("unable to encode private key");
+        document.as_bytes().to_vec()
+    }
+
+    pub fn derive_child(&self, index: u32) -> ExtendedPrivateKey {
+        let mut data = if index >= HARDENED_OFFSET {
+            let mut data = vec![0u8];
+            data.extend(&self.secret_key.to_bytes()[..]);
+            data
+        } else {
+            self.compressed_public_key()
+        };
+        data.extend(index.to_be_bytes());
+
+        loop {
+            let i = hmac_sha512(&self.chain_code, &data);
+            let mut tweak = FieldBytes::default();
+            tweak.copy_from_slice(&i[..32]);
+            let tweak_scalar = <Scalar as Reduce<U256>>::reduce_bytes(&tweak);
+            // the tweak must be a canonical scalar, i.e. reduction changed nothing
+            let canonical = tweak_scalar.to_bytes()[..] == i[..32];
+            let child_scalar = tweak_scalar + *self.secret_key.to_nonzero_scalar();
+            let child = Option::<NonZeroScalar>::from(NonZeroScalar::new(child_scalar));
+            if let (true, Some(child)) = (canonical, child) {
+                return ExtendedPrivateKey {
+                    secret_key: SecretKey::from(child),
+                    chain_code: i[32..].try_into().unwrap(),
+                };
+            }
+            data = vec![1u8];
+            data.extend(&i[32..]);
+            data.extend(index.to_be_bytes());
+        }
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> ExtendedPrivateKey {
+        let mut key = self.clone();
+        for index in path.get_indexes() {
+            key = key.derive_child(*index);
+        }
+        key
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+use std::str::FromStr;
+
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use serde::{Deserialize, Serialize};
+
+use crate::script::{Opcode, Script};
+use crate::wallet::{self, convert_address, WalletError};
+
+pub const SECRET_LEN: usize = 32;
+const DESCRIPTOR_PREFIX: &str = "htlc(";
+const PUB_KEY_HASH_LEN: usize = 20;
+
+// A hash time-locked contract: `recipient` spends it with the preimage of
+// `secret_hash`, or `refund` does once `lock_time` has passed. Written as
+// htlc(secret_hash,recipient,refund,lock_time), which is also how senders
+// pay to it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HtlcContract {
+    secret_hash: Vec<u8>,
+    recipient: Vec<u8>,
+    refund: Vec<u8>,
+    lock_time: u32,
+}
+
+impl HtlcContract {
+    pub fn new(
+        secret_hash: Vec<u8>,
+        recipient: Vec<u8>,
+        refund: Vec<u8>,
+        lock_time: u32,
+    ) -> Result<HtlcContract, WalletError> {
+        if secret_hash.len() != SECRET_LEN {
+            return Err(WalletError::InvalidContract(format!(
+                "the secret hash must be {} bytes",
+                SECRET_LEN
+            )));
+        }
+        if recipient.len() != PUB_KEY_HASH_LEN || refund.len() != PUB_KEY_HASH_LEN {
+            return Err(WalletError::InvalidContract(String::from(
+                "recipient and refunder must be key addresses",
+            )));
+        }
+        if lock_time == 0 {
+            return Err(WalletError::InvalidContract(String::from(
+                "the lock time must be set",
+            )));
+        }
+        Ok(HtlcContract {
+            secret_hash,
+            recipient,
+            refund,
+            lock_time,
+        })
+    }
+
+    pub fn is_descriptor(destination: &str) -> bool {
+        destination.starts_with(DESCRIPTOR_PREFIX)
+    }
+
+    pub fn get_secret_hash(&self) -> &[u8] {
+        self.secret_hash.as_slice()
+    }
+
+    pub fn get_recipient(&self) -> &[u8] {
+        self.recipient.as_slice()
+    }
+
+    pub fn get_refund(&self) -> &[u8] {
+        self.refund.as_slice()
+    }
+
+    pub fn get_lock_time(&self) -> u32 {
+        self.lock_time
+    }
+
+    // stands in for the pub_key_hash of outputs locked to the contract
+    pub fn hash(&self) -> Vec<u8> {
+        Script::htlc(self).hash()
+    }
+
+    pub fn matches_secret(&self, secret: &[u8]) -> bool {
+        secret.len() == SECRET_LEN && hash_secret(secret) == self.secret_hash
+    }
+
+    // the secret revealed by the unlocking script of a redeeming input
+    pub fn find_secret(&self, script_sig: &Script) -> Option<Vec<u8>> {
+        match script_sig.get_ops() {
+            [Opcode::Push(_), Opcode::Push(_), Opcode::Push(secret), Opcode::Push(_)]
+                if self.matches_secret(secret) =>
+            {
+                Some(secret.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn new_secret() -> Vec<u8> {
+    crate::utils::random_bytes(SECRET_LEN)
+}
+
+pub fn hash_secret(secret: &[u8]) -> Vec<u8> {
+    crate::utils::sha256_digest(secret)
+}
+
+impl fmt::Display for HtlcContract {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{},{},{},{})",
+            DESCRIPTOR_PREFIX,
+            HEXLOWER.encode(self.secret_hash.as_slice()),
+            convert_address(self.recipient.as_slice()),
+            convert_address(self.refund.as_slice()),
+            self.lock_time
+        )
+    }
+}
+
+impl FromStr for HtlcContract {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: &str| WalletError::InvalidContract(format!("{}: {}", s, e));
+        let body = s
+            .trim()
+            .strip_prefix(DESCRIPTOR_PREFIX)
+            .and_then(|body| body.strip_suffix(')'))
+            .ok_or_else(|| invalid("expected htlc(secret_hash,recipient,refund,lock_time)"))?;
+        let parts: Vec<&str> = body.split(',').map(|part| part.trim()).collect();
+        if parts.len() != 4 {
+            return Err(invalid("expected four fields"));
+        }
+        let secret_hash = HEXLOWER_PERMISSIVE
+            .decode(parts[0].as_bytes())
+            .map_err(|e| invalid(e.to_string().as_str()))?;
+        let mut pub_key_hashes = vec![];
+        for address in &parts[1..3] {
+            if !wallet::validate_address(address) || wallet::is_script_address(address) {
+                return Err(invalid(
+                    format!("{} is not a key address", address).as_str(),
+                ));
+            }
+            let payload = crate::utils::base58_decode(address);
+            pub_key_hashes.push(payload[1..payload.len() - wallet::ADDRESS_CHECK_SUM_LEN].to_vec());
+        }
+        let lock_time = parts[3].parse().map_err(|_| invalid("invalid lock time"))?;
+        let refund = pub_key_hashes.pop().unwrap();
+        let recipient = pub_key_hashes.pop().unwrap();
+        HtlcContract::new(secret_hash, recipient, refund, lock_time)
+    }
+}
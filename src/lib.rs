@@ -1,11 +1,23 @@
+pub mod asset;
 pub mod block;
+pub mod block_template;
 pub mod blockchain;
+pub mod coin_selection;
 pub mod config;
+pub mod fee_estimator;
+pub mod hd_key;
+pub mod htlc;
 pub mod memory_pool;
+pub mod miner;
+pub mod multisig;
+pub mod nft;
 pub mod node;
 pub mod proof_of_work;
+pub mod psbt;
+pub mod script;
 pub mod server;
 pub mod transaction;
 pub mod utils;
 pub mod utxo_set;
 pub mod wallet;
+pub mod wallet_ledger;
@@ -1,19 +1,31 @@
 use crypto::util;
+use custom_blockchain::asset::AssetIssuance;
 use custom_blockchain::blockchain::Blockchain;
+use custom_blockchain::coin_selection::{CoinControl, CoinSelectionStrategy};
 use custom_blockchain::config::GLOBAL_CONFIG;
-use custom_blockchain::server::{send_tx, Server, CENTERAL_NODE};
-use custom_blockchain::transaction::Transaction;
+use custom_blockchain::fee_estimator::{self, FeeEstimator};
+use custom_blockchain::htlc::{self, HtlcContract};
+use custom_blockchain::multisig::MultisigPolicy;
+use custom_blockchain::psbt::PartiallySignedTransaction;
+use custom_blockchain::script::LOCK_TIME_THRESHOLD;
+use custom_blockchain::server::{send_request, send_tx, Package, Server, CENTERAL_NODE};
+use custom_blockchain::transaction::{destination_hash, Transaction};
 // use custom_blockchain::{
 //     convert_address, hash_pub_key, send_tx, utils, validate_address, Blockchain, Server,
 //     Transaction, UTXOSet, Wallets, ADDRESS_CHECK_SUM_LEN, CENTERAL_NODE, GLOBAL_CONFIG,
 // };
 use custom_blockchain::utils::{self, *};
-use custom_blockchain::utxo_set::UTXOSet;
+use custom_blockchain::utxo_set::{OutPoint, UTXOSet, UnspentOutput};
 use custom_blockchain::wallet::{
-    convert_address, hash_pub_key, validate_address, Wallets, ADDRESS_CHECK_SUM_LEN,
+    convert_address, decode_private_key, encode_private_key, encode_private_key_pem, hash_pub_key,
+    is_script_address, validate_address, validate_destination, verify_message, Wallets,
+    ADDRESS_CHECK_SUM_LEN, DEFAULT_ACCOUNT_PATH,
 };
-use data_encoding::HEXLOWER;
+use custom_blockchain::wallet_ledger::WalletLedger;
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
 use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
 use structopt::StructOpt;
 
 const MINE_TRUE: usize = 1;
@@ -33,14 +45,38 @@ enum Command {
         address: String,
     },
     #[structopt(name = "createwallet", about = "Create a new wallet")]
-    Createwallet,
+    Createwallet {
+        #[structopt(
+            long = "path",
+            help = "Account derivation path used when the wallet seed is created"
+        )]
+        path: Option<String>,
+    },
+    #[structopt(
+        name = "restorewallet",
+        about = "Restore wallet addresses from a mnemonic"
+    )]
+    RestoreWallet {
+        #[structopt(name = "mnemonic", help = "Recovery phrase, quoted")]
+        mnemonic: String,
+        #[structopt(long = "passphrase", default_value = "", help = "BIP39 passphrase")]
+        passphrase: String,
+        #[structopt(long = "path", help = "Account derivation path")]
+        path: Option<String>,
+        #[structopt(
+            long = "count",
+            default_value = "1",
+            help = "Minimum number of addresses to regenerate"
+        )]
+        count: u32,
+    },
     #[structopt(
         name = "getbalance",
-        about = "Get the wallet balance of the target address"
+        about = "Get the balance of an address, or of the whole wallet"
     )]
     GetBalance {
         #[structopt(name = "address", help = "The wallet address")]
-        address: String,
+        address: Option<String>,
     },
     #[structopt(name = "listaddresses", about = "Print local wallet addres")]
     ListAddresses,
@@ -54,6 +90,498 @@ enum Command {
         amount: i32,
         #[structopt(name = "mine", help = "Mine immediately on the same node")]
         mine: usize,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(
+            long = "strategy",
+            default_value = "auto",
+            help = "Coin selection: auto, bnb, largest, oldest or knapsack"
+        )]
+        strategy: CoinSelectionStrategy,
+        #[structopt(
+            long = "inputs",
+            use_delimiter = true,
+            help = "Spend exactly these txid:vout outputs, comma separated"
+        )]
+        inputs: Vec<OutPoint>,
+        #[structopt(
+            long = "asset",
+            help = "Send units of this asset id instead of coins, the fee is paid in coins"
+        )]
+        asset: Option<String>,
+    },
+    #[structopt(name = "sendmany", about = "Pay several recipients in one transaction")]
+    SendMany {
+        #[structopt(name = "from", help = "Source wallet address")]
+        from: String,
+        #[structopt(name = "recipients", help = "Payments written as address:amount")]
+        recipients: Vec<String>,
+        #[structopt(
+            long = "file",
+            help = "CSV file of address,amount lines, or a .json file of {address, amount} objects"
+        )]
+        file: Option<String>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(
+            long = "strategy",
+            default_value = "auto",
+            help = "Coin selection: auto, bnb, largest, oldest or knapsack"
+        )]
+        strategy: CoinSelectionStrategy,
+        #[structopt(
+            long = "inputs",
+            use_delimiter = true,
+            help = "Spend exactly these txid:vout outputs, comma separated"
+        )]
+        inputs: Vec<OutPoint>,
+    },
+    #[structopt(
+        name = "createpsbt",
+        about = "Create an unsigned transaction file, no private key needed"
+    )]
+    CreatePsbt {
+        #[structopt(name = "from", help = "Source address, may be watch-only")]
+        from: String,
+        #[structopt(name = "recipients", help = "Payments written as address:amount")]
+        recipients: Vec<String>,
+        #[structopt(
+            long = "file",
+            help = "CSV file of address,amount lines, or a .json file of {address, amount} objects"
+        )]
+        file: Option<String>,
+        #[structopt(
+            long = "change-address",
+            help = "Address receiving the change, the source address when omitted"
+        )]
+        change_address: Option<String>,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(
+            long = "strategy",
+            default_value = "auto",
+            help = "Coin selection: auto, bnb, largest, oldest or knapsack"
+        )]
+        strategy: CoinSelectionStrategy,
+        #[structopt(
+            long = "inputs",
+            use_delimiter = true,
+            help = "Spend exactly these txid:vout outputs, comma separated"
+        )]
+        inputs: Vec<OutPoint>,
+        #[structopt(
+            long = "lock-time",
+            default_value = "0",
+            help = "Earliest block height, or unix time, the transaction may be mined at"
+        )]
+        lock_time: u32,
+        #[structopt(
+            long = "relative-lock",
+            default_value = "0",
+            help = "Blocks each spent output must have been confirmed for"
+        )]
+        relative_lock: u32,
+        #[structopt(long = "out", help = "File to write the unsigned transaction to")]
+        out: String,
+    },
+    #[structopt(
+        name = "decodepsbt",
+        about = "Show the inputs, outputs and fee of a transaction file"
+    )]
+    DecodePsbt {
+        #[structopt(name = "file", help = "Transaction file")]
+        file: String,
+    },
+    #[structopt(
+        name = "signpsbt",
+        about = "Sign the inputs of a transaction file this wallet has keys for"
+    )]
+    SignPsbt {
+        #[structopt(name = "file", help = "Transaction file")]
+        file: String,
+        #[structopt(
+            long = "out",
+            help = "Where to write the result, the input file by default"
+        )]
+        out: Option<String>,
+    },
+    #[structopt(
+        name = "combinepsbt",
+        about = "Merge the signatures of copies signed by different co-signers"
+    )]
+    CombinePsbt {
+        #[structopt(
+            name = "files",
+            required = true,
+            help = "Signed copies of one transaction"
+        )]
+        files: Vec<String>,
+        #[structopt(long = "out", help = "File to write the combined transaction to")]
+        out: String,
+    },
+    #[structopt(
+        name = "getpubkey",
+        about = "Print the public key of an address, e.g. to share with co-signers"
+    )]
+    GetPubKey {
+        #[structopt(name = "address", help = "The wallet address")]
+        address: String,
+    },
+    #[structopt(
+        name = "createmultisig",
+        about = "Create an m-of-n multisig destination and track it in the wallet"
+    )]
+    CreateMultisig {
+        #[structopt(name = "required", help = "Number of signatures needed to spend")]
+        required: usize,
+        #[structopt(
+            name = "keys",
+            required = true,
+            help = "Wallet addresses with a known public key, or hex public keys"
+        )]
+        keys: Vec<String>,
+    },
+    #[structopt(
+        name = "initiateswap",
+        about = "Lock coins in a swap contract the recipient redeems with a secret"
+    )]
+    InitiateSwap {
+        #[structopt(name = "from", help = "Source wallet address, also receives refunds")]
+        from: String,
+        #[structopt(name = "to", help = "Address of the other party on this chain")]
+        to: String,
+        #[structopt(name = "amount", help = "Amount to lock")]
+        amount: i32,
+        #[structopt(
+            long = "secret-hash",
+            help = "Secret hash of a swap the other party initiated, a new secret is made when omitted"
+        )]
+        secret_hash: Option<String>,
+        #[structopt(
+            long = "timeout",
+            default_value = "48",
+            help = "Blocks until the coins can be refunded"
+        )]
+        timeout: u32,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(
+        name = "redeemswap",
+        about = "Claim the coins of a swap contract with its secret"
+    )]
+    RedeemSwap {
+        #[structopt(name = "contract", help = "Contract output as txid:vout")]
+        contract: OutPoint,
+        #[structopt(name = "secret", help = "Hex secret matching the secret hash")]
+        secret: String,
+        #[structopt(
+            long = "to",
+            help = "Address receiving the coins, the contract recipient when omitted"
+        )]
+        to: Option<String>,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(
+        name = "refundswap",
+        about = "Take back the coins of a swap contract after its timeout"
+    )]
+    RefundSwap {
+        #[structopt(name = "contract", help = "Contract output as txid:vout")]
+        contract: OutPoint,
+        #[structopt(
+            long = "to",
+            help = "Address receiving the coins, the contract refunder when omitted"
+        )]
+        to: Option<String>,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(
+        name = "auditswap",
+        about = "Show the terms and state of a swap contract, and its secret once redeemed"
+    )]
+    AuditSwap {
+        #[structopt(name = "contract", help = "Contract output as txid:vout")]
+        contract: OutPoint,
+    },
+    #[structopt(
+        name = "anchor",
+        about = "Timestamp a file or hex digest by embedding it in a data output"
+    )]
+    Anchor {
+        #[structopt(name = "data", help = "File to hash, or a hex digest")]
+        data: String,
+        #[structopt(
+            long = "from",
+            help = "Address paying the fee, the wallet address with the most spendable coins when omitted"
+        )]
+        from: Option<String>,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(
+        name = "findanchor",
+        about = "Find the block that anchored a file or hex digest"
+    )]
+    FindAnchor {
+        #[structopt(name = "data", help = "File to hash, or a hex digest")]
+        data: String,
+    },
+    #[structopt(name = "issueasset", about = "Create a new asset with a fixed supply")]
+    IssueAsset {
+        #[structopt(name = "from", help = "Address paying the fee")]
+        from: String,
+        #[structopt(name = "name", help = "Asset name")]
+        name: String,
+        #[structopt(name = "supply", help = "Number of units issued")]
+        supply: i32,
+        #[structopt(
+            long = "to",
+            help = "Address receiving the supply, the paying address when omitted"
+        )]
+        to: Option<String>,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(
+        name = "mintnft",
+        about = "Mint a unique token for a metadata file or hex digest"
+    )]
+    MintNft {
+        #[structopt(name = "from", help = "Address paying the fee")]
+        from: String,
+        #[structopt(name = "metadata", help = "Metadata file to hash, or a hex digest")]
+        metadata: String,
+        #[structopt(
+            long = "to",
+            help = "Address receiving the token, the paying address when omitted"
+        )]
+        to: Option<String>,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(name = "sendnft", about = "Transfer a token, the fee is paid in coins")]
+    SendNft {
+        #[structopt(name = "from", help = "Address holding the token")]
+        from: String,
+        #[structopt(name = "to", help = "Destination wallet address")]
+        to: String,
+        #[structopt(name = "token", help = "Token id")]
+        token: String,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(name = "burnnft", about = "Destroy a token for good")]
+    BurnNft {
+        #[structopt(name = "from", help = "Address holding the token")]
+        from: String,
+        #[structopt(name = "token", help = "Token id")]
+        token: String,
+        #[structopt(long = "fee", help = "Transaction fee, estimated when omitted")]
+        fee: Option<i32>,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(
+        name = "listnfts",
+        about = "List the tokens held by an address, or by the whole wallet"
+    )]
+    ListNfts {
+        #[structopt(name = "address", help = "The wallet address")]
+        address: Option<String>,
+    },
+    #[structopt(
+        name = "tracenft",
+        about = "Trace a token from its mint through every transfer"
+    )]
+    TraceNft {
+        #[structopt(name = "token", help = "Token id")]
+        token: String,
+    },
+    #[structopt(
+        name = "broadcastpsbt",
+        about = "Verify a fully signed transaction file and send it"
+    )]
+    BroadcastPsbt {
+        #[structopt(name = "file", help = "Transaction file")]
+        file: String,
+        #[structopt(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[structopt(
+        name = "generate",
+        about = "Mine blocks with only a coinbase on the same node, maturing earlier rewards"
+    )]
+    Generate {
+        #[structopt(name = "address", help = "Address to send the block rewards to")]
+        address: String,
+        #[structopt(
+            long = "blocks",
+            default_value = "1",
+            help = "Number of blocks to mine"
+        )]
+        blocks: usize,
+    },
+    #[structopt(name = "estimatefee", about = "Estimate the fee rate per 1000 bytes")]
+    EstimateFee {
+        #[structopt(name = "target_blocks", help = "Blocks within which to confirm")]
+        target_blocks: usize,
+    },
+    #[structopt(
+        name = "getblocktemplate",
+        about = "Request a block template from the node for external mining"
+    )]
+    GetBlockTemplate {
+        #[structopt(name = "mining_address", help = "Address to send the block reward to")]
+        mining_address: String,
+        #[structopt(
+            long = "extra-nonce",
+            help = "Hex data put in the coinbase, for fresh work once the nonce space runs out"
+        )]
+        extra_nonce: Option<String>,
+    },
+    #[structopt(
+        name = "submitblock",
+        about = "Submit a solved block template to the node"
+    )]
+    SubmitBlock {
+        #[structopt(name = "template_id", help = "Id returned by getblocktemplate")]
+        template_id: String,
+        #[structopt(name = "timestamp", help = "Timestamp used in the solved header")]
+        timestamp: i64,
+        #[structopt(name = "nonce", help = "Nonce that solves the header")]
+        nonce: i64,
+    },
+    #[structopt(name = "encryptwallet", about = "Encrypt the wallet with a passphrase")]
+    EncryptWallet {
+        #[structopt(name = "passphrase", help = "Passphrase protecting the wallet keys")]
+        passphrase: String,
+    },
+    #[structopt(
+        name = "walletpassphrase",
+        about = "Unlock the wallet for signing for a number of seconds"
+    )]
+    WalletPassphrase {
+        #[structopt(name = "passphrase", help = "Wallet passphrase")]
+        passphrase: String,
+        #[structopt(name = "timeout", help = "Seconds to keep the wallet unlocked")]
+        timeout: u64,
+    },
+    #[structopt(name = "walletlock", about = "Lock the wallet again")]
+    WalletLock,
+    #[structopt(name = "dumpprivkey", about = "Print the private key of an address")]
+    DumpPrivKey {
+        #[structopt(name = "address", help = "The wallet address")]
+        address: String,
+        #[structopt(long = "pem", help = "Print a PEM block instead of base58")]
+        pem: bool,
+    },
+    #[structopt(
+        name = "signmessage",
+        about = "Sign a message to prove control of an address"
+    )]
+    SignMessage {
+        #[structopt(name = "address", help = "The wallet address")]
+        address: String,
+        #[structopt(name = "message", help = "The message to sign")]
+        message: String,
+    },
+    #[structopt(
+        name = "verifymessage",
+        about = "Check a signature made by signmessage"
+    )]
+    VerifyMessage {
+        #[structopt(name = "address", help = "The address that signed")]
+        address: String,
+        #[structopt(name = "signature", help = "Signature printed by signmessage")]
+        signature: String,
+        #[structopt(name = "message", help = "The signed message")]
+        message: String,
+    },
+    #[structopt(name = "importprivkey", about = "Import a private key into the wallet")]
+    ImportPrivKey {
+        #[structopt(
+            name = "key",
+            help = "Base58 key from dumpprivkey, or a file holding a PEM key"
+        )]
+        key: String,
+        #[structopt(long = "no-rescan", help = "Skip the rescan after importing")]
+        no_rescan: bool,
+    },
+    #[structopt(
+        name = "importaddress",
+        about = "Watch an address or hex public key without its private key"
+    )]
+    ImportAddress {
+        #[structopt(name = "address", help = "Address or hex encoded public key")]
+        address: String,
+        #[structopt(long = "no-rescan", help = "Skip the rescan after importing")]
+        no_rescan: bool,
+    },
+    #[structopt(
+        name = "rescanwallet",
+        about = "Rebuild the UTXO set and report wallet balances"
+    )]
+    RescanWallet,
+    #[structopt(
+        name = "listtransactions",
+        about = "List the wallet's incoming and outgoing transactions"
+    )]
+    ListTransactions {
+        #[structopt(
+            long = "address",
+            help = "Only list transactions touching this address"
+        )]
+        address: Option<String>,
+        #[structopt(
+            long = "count",
+            default_value = "10",
+            help = "Number of most recent transactions to list"
+        )]
+        count: usize,
+    },
+    #[structopt(name = "setlabel", about = "Label an address or a transaction")]
+    SetLabel {
+        #[structopt(name = "target", help = "Address or transaction id")]
+        target: String,
+        #[structopt(name = "label", help = "Label text, empty to remove the label")]
+        label: String,
+    },
+    #[structopt(name = "listunspent", about = "List the wallet's unspent outputs")]
+    ListUnspent {
+        #[structopt(long = "address", help = "Only list outputs of this address")]
+        address: Option<String>,
+    },
+    #[structopt(
+        name = "freezeutxo",
+        about = "Exclude an unspent output from coin selection"
+    )]
+    FreezeUtxo {
+        #[structopt(name = "outpoint", help = "Output as txid:vout")]
+        outpoint: OutPoint,
+    },
+    #[structopt(name = "unfreezeutxo", about = "Make a frozen output spendable again")]
+    UnfreezeUtxo {
+        #[structopt(name = "outpoint", help = "Output as txid:vout")]
+        outpoint: OutPoint,
     },
     #[structopt(name = "printchain", about = "Print blockchain all block")]
     Printchain,
@@ -82,32 +610,135 @@ fn main() {
             utxo_set.reindex();
             println!("Done!");
         }
-        Command::Createwallet => {
+        Command::Createwallet { path } => {
             let mut wallet = Wallets::new();
-            let address = wallet.create_wallet();
+            if wallet.has_seed() && path.is_some() {
+                panic!("ERROR: The wallet already has a seed, --path only applies to a new one")
+            }
+            if !wallet.has_seed() {
+                let path = path.unwrap_or_else(|| String::from(DEFAULT_ACCOUNT_PATH));
+                let mnemonic = wallet
+                    .generate_seed(path.as_str())
+                    .unwrap_or_else(|e| panic!("ERROR: {}", e));
+                println!("New wallet seed created, write down your recovery phrase:");
+                println!("{}", mnemonic);
+            }
+            let address = wallet
+                .create_wallet()
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
             println!("Your new address: {}", address)
         }
-        Command::GetBalance { address } => {
-            let address_valid = validate_address(address.as_str());
+        Command::RestoreWallet {
+            mnemonic,
+            passphrase,
+            path,
+            count,
+        } => {
+            let mut used_pub_key_hashes = HashSet::new();
+            if Blockchain::exists() {
+                let mut block_iterator = Blockchain::new_blockchain().iterator();
+                while let Some(block) = block_iterator.next() {
+                    for tx in block.get_transactions() {
+                        for output in tx.get_vout() {
+                            used_pub_key_hashes.insert(output.get_pub_key_hash().to_vec());
+                        }
+                    }
+                }
+            }
+            let path = path.unwrap_or_else(|| String::from(DEFAULT_ACCOUNT_PATH));
+            let mut wallets = Wallets::new();
+            let restored = wallets
+                .restore_from_mnemonic(
+                    mnemonic.as_str(),
+                    passphrase.as_str(),
+                    path.as_str(),
+                    count,
+                    |pub_key_hash| used_pub_key_hashes.contains(pub_key_hash),
+                )
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            println!("Restored {} addresses", restored);
+        }
+        Command::GetBalance {
+            address: Some(address),
+        } => {
+            let address_valid = validate_destination(address.as_str());
             if address_valid == false {
                 panic!("ERROR: Address is not valid")
             }
-            let payload = utils::base58_decode(address.as_str());
-            let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
-
-            let blockchain = Blockchain::new_blockchain();
-            let utxo_set = UTXOSet::new(blockchain);
-            let utxos = utxo_set.find_utxo(pub_key_hash);
-            let mut balance = 0;
-            for utxo in utxos {
-                balance += utxo.get_value();
-            }
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let balance = address_balance(&utxo_set, address.as_str());
             println!("Balance of {}: {}", address, balance);
+            let immature = address_immature_balance(&utxo_set, address.as_str());
+            if immature > 0 {
+                println!("Immature balance: {}", immature);
+            }
+            print_asset_balances(&utxo_set, &[address]);
+        }
+        Command::GetBalance { address: None } => {
+            let wallets = Wallets::new();
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            // change addresses are part of get_addresses
+            let balance: i32 = wallets
+                .get_addresses()
+                .iter()
+                .map(|address| address_balance(&utxo_set, address.as_str()))
+                .sum();
+            let watch_only: i32 = wallets
+                .get_watch_only_addresses()
+                .iter()
+                .map(|address| address_balance(&utxo_set, address.as_str()))
+                .sum();
+            let multisig: i32 = wallets
+                .get_multisig_policies()
+                .iter()
+                .map(|policy| address_balance(&utxo_set, policy.get_address().as_str()))
+                .sum();
+            let immature: i32 = wallets
+                .get_addresses()
+                .iter()
+                .map(|address| address_immature_balance(&utxo_set, address.as_str()))
+                .sum();
+            println!("Wallet balance: {}", balance);
+            if immature > 0 {
+                println!("Immature balance: {}", immature);
+            }
+            if watch_only > 0 {
+                println!("Watch-only balance: {}", watch_only);
+            }
+            if multisig > 0 {
+                println!("Multisig balance: {}", multisig);
+            }
+            print_asset_balances(&utxo_set, wallets.get_addresses().as_slice());
         }
         Command::ListAddresses => {
             let wallets = Wallets::new();
             for address in wallets.get_addresses() {
-                println!("{}", address)
+                let change = if wallets.is_internal(address.as_str()) {
+                    " (change)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{}{}{}",
+                    address,
+                    change,
+                    label_suffix(&wallets, address.as_str())
+                )
+            }
+            for address in wallets.get_watch_only_addresses() {
+                println!(
+                    "{} (watch-only){}",
+                    address,
+                    label_suffix(&wallets, address.as_str())
+                )
+            }
+            for policy in wallets.get_multisig_policies() {
+                println!(
+                    "{} (multisig {}-of-{})",
+                    policy.get_address(),
+                    policy.get_required(),
+                    policy.get_pub_keys().len()
+                )
             }
         }
         Command::Send {
@@ -115,46 +746,952 @@ fn main() {
             to,
             amount,
             mine,
+            fee,
+            strategy,
+            inputs,
+            asset,
         } => {
             if !validate_address(from.as_str()) {
                 panic!("ERROR: Sender address is not valid")
             }
-            if !validate_address(to.as_str()) {
+            if !validate_destination(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            let blockchain = Blockchain::new_blockchain();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            let coin_control = CoinControl::new(strategy, inputs);
+
+            let transaction = match asset {
+                Some(asset) => {
+                    let asset = HEXLOWER_PERMISSIVE
+                        .decode(asset.as_bytes())
+                        .unwrap_or_else(|e| panic!("ERROR: Invalid asset id: {}", e));
+                    Transaction::new_asset_transaction(
+                        from.as_str(),
+                        to.as_str(),
+                        asset.as_slice(),
+                        amount,
+                        fee,
+                        &coin_control,
+                        &utxo_set,
+                        utils::base58_decode,
+                        utils::sha256_digest,
+                        utils::ecdsa_p256_sha256_sign_digest,
+                    )
+                }
+                None => Transaction::new_utxo_transaction(
+                    from.as_str(),
+                    to.as_str(),
+                    amount,
+                    fee,
+                    &coin_control,
+                    &utxo_set,
+                    utils::base58_decode,
+                    utils::sha256_digest,
+                    utils::ecdsa_p256_sha256_sign_digest,
+                ),
+            }
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+
+            submit_transaction(&utxo_set, transaction, from.as_str(), mine == MINE_TRUE);
+            println!("Success!")
+        }
+        Command::SendMany {
+            from,
+            recipients,
+            file,
+            mine,
+            fee,
+            strategy,
+            inputs,
+        } => {
+            if !validate_address(from.as_str()) {
+                panic!("ERROR: Sender address is not valid")
+            }
+            let mut payments: Vec<(String, i32)> = recipients
+                .iter()
+                .map(|recipient| parse_payment(recipient.as_str(), ':'))
+                .collect();
+            if let Some(file) = file {
+                payments.extend(read_payments_file(file.as_str()));
+            }
+            let blockchain = Blockchain::new_blockchain();
+            let utxo_set = UTXOSet::new(blockchain);
+
+            let transaction = Transaction::new_utxo_transaction_many(
+                from.as_str(),
+                payments.as_slice(),
+                fee,
+                &CoinControl::new(strategy, inputs),
+                &utxo_set,
+                utils::base58_decode,
+                utils::sha256_digest,
+                utils::ecdsa_p256_sha256_sign_digest,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let txid_hex = HEXLOWER.encode(transaction.get_id());
+
+            submit_transaction(&utxo_set, transaction, from.as_str(), mine);
+            println!("Paid {} recipients in {}", payments.len(), txid_hex)
+        }
+        Command::CreatePsbt {
+            from,
+            recipients,
+            file,
+            change_address,
+            fee,
+            strategy,
+            inputs,
+            lock_time,
+            relative_lock,
+            out,
+        } => {
+            if !validate_destination(from.as_str()) {
+                panic!("ERROR: Sender address is not valid")
+            }
+            if let Some(change_address) = &change_address {
+                if !validate_destination(change_address.as_str()) {
+                    panic!("ERROR: Change address is not valid")
+                }
+            }
+            let mut payments: Vec<(String, i32)> = recipients
+                .iter()
+                .map(|recipient| parse_payment(recipient.as_str(), ':'))
+                .collect();
+            if let Some(file) = file {
+                payments.extend(read_payments_file(file.as_str()));
+            }
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let mut psbt = PartiallySignedTransaction::create(
+                from.as_str(),
+                payments.as_slice(),
+                change_address.as_deref(),
+                fee,
+                &CoinControl::new(strategy, inputs),
+                &utxo_set,
+                utils::base58_decode,
+                utils::sha256_digest,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            if lock_time > 0 || relative_lock > 0 {
+                psbt.set_locks(lock_time, relative_lock, utils::sha256_digest);
+            }
+            write_psbt(out.as_str(), &psbt);
+            println!(
+                "Unsigned transaction with {} inputs written to {}",
+                psbt.get_prev_outputs().len(),
+                out
+            );
+        }
+        Command::DecodePsbt { file } => {
+            let psbt = read_psbt(file.as_str());
+            let tx = psbt.get_transaction();
+            for (idx, (input, prev_output)) in
+                tx.get_vin().iter().zip(psbt.get_prev_outputs()).enumerate()
+            {
+                let signed = match psbt.get_input_policy(idx) {
+                    Some(policy) => format!(
+                        "{} of {} signatures",
+                        input
+                            .get_script_sig()
+                            .count_signatures(policy.get_pub_keys().len()),
+                        policy.get_required()
+                    ),
+                    None if input.get_script_sig().is_empty() => String::from("unsigned"),
+                    None => String::from("signed"),
+                };
+                let relative_lock = match input.get_sequence() {
+                    0 => String::new(),
+                    blocks => format!(", relative lock = {} blocks", blocks),
+                };
+                println!(
+                    "-- Input txid = {}, vout = {}, value = {}, from = {} ({}){}",
+                    HEXLOWER.encode(input.get_txid()),
+                    input.get_vout(),
+                    prev_output.get_value(),
+                    prev_output.get_destination(),
+                    signed,
+                    relative_lock
+                );
+            }
+            for output in tx.get_vout() {
+                println!(
+                    "-- Output value = {}, to = {}",
+                    output.get_value(),
+                    output.get_destination()
+                );
+            }
+            println!("Fee: {}", psbt.get_fee());
+            if tx.get_lock_time() > 0 {
+                println!("Locked until {}", describe_lock_time(tx.get_lock_time()));
+            }
+        }
+        Command::SignPsbt { file, out } => {
+            let mut psbt = read_psbt(file.as_str());
+            let wallets = Wallets::new();
+            let signed = psbt
+                .sign(
+                    &wallets,
+                    utils::sha256_digest,
+                    utils::ecdsa_p256_sha256_sign_digest,
+                )
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let out = out.unwrap_or(file);
+            write_psbt(out.as_str(), &psbt);
+            println!(
+                "Added {} signatures, {} inputs still unsigned, written to {}",
+                signed,
+                psbt.count_unsigned(),
+                out
+            );
+        }
+        Command::CombinePsbt { files, out } => {
+            let mut psbt = read_psbt(files[0].as_str());
+            for file in &files[1..] {
+                psbt.combine(&read_psbt(file.as_str()))
+                    .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            }
+            write_psbt(out.as_str(), &psbt);
+            println!(
+                "Combined {} files, {} inputs still unsigned, written to {}",
+                files.len(),
+                psbt.count_unsigned(),
+                out
+            );
+        }
+        Command::GetPubKey { address } => {
+            let wallets = Wallets::new();
+            let pub_key = wallets
+                .get_public_key(address.as_str())
+                .unwrap_or_else(|| panic!("ERROR: no public key known for {}", address));
+            println!("{}", HEXLOWER.encode(pub_key));
+        }
+        Command::CreateMultisig { required, keys } => {
+            let mut wallets = Wallets::new();
+            let pub_keys = keys
+                .iter()
+                .map(|key| match wallets.get_public_key(key.as_str()) {
+                    Some(pub_key) => pub_key.to_vec(),
+                    None => HEXLOWER_PERMISSIVE
+                        .decode(key.as_bytes())
+                        .unwrap_or_else(|_| panic!("ERROR: {} has no known public key", key)),
+                })
+                .collect();
+            let policy =
+                MultisigPolicy::new(required, pub_keys).unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let address = policy.get_address();
+            let descriptor = wallets.add_multisig(policy);
+            println!("Multisig address: {}", address);
+            println!("Descriptor: {}", descriptor);
+        }
+        Command::InitiateSwap {
+            from,
+            to,
+            amount,
+            secret_hash,
+            timeout,
+            fee,
+            mine,
+        } => {
+            if !validate_address(from.as_str()) || is_script_address(from.as_str()) {
+                panic!("ERROR: Sender address is not valid")
+            }
+            if !validate_address(to.as_str()) || is_script_address(to.as_str()) {
                 panic!("ERROR: Recipient address is not valid")
             }
+            let (secret, secret_hash) = match secret_hash {
+                Some(secret_hash) => {
+                    let secret_hash = HEXLOWER_PERMISSIVE
+                        .decode(secret_hash.trim().as_bytes())
+                        .unwrap_or_else(|e| panic!("ERROR: invalid secret hash: {}", e));
+                    (None, secret_hash)
+                }
+                None => {
+                    let secret = htlc::new_secret();
+                    let secret_hash = htlc::hash_secret(secret.as_slice());
+                    (Some(secret), secret_hash)
+                }
+            };
             let blockchain = Blockchain::new_blockchain();
             let utxo_set = UTXOSet::new(blockchain.clone());
+            let lock_time = blockchain.get_best_height() as u32 + timeout;
+            let contract = HtlcContract::new(
+                secret_hash,
+                destination_hash(to.as_str(), utils::base58_decode).unwrap(),
+                destination_hash(from.as_str(), utils::base58_decode).unwrap(),
+                lock_time,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
 
             let transaction = Transaction::new_utxo_transaction(
                 from.as_str(),
-                to.as_str(),
+                contract.to_string().as_str(),
                 amount,
+                fee,
+                &CoinControl::new(CoinSelectionStrategy::default(), vec![]),
                 &utxo_set,
                 utils::base58_decode,
                 utils::sha256_digest,
                 utils::ecdsa_p256_sha256_sign_digest,
-            );
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let vout = transaction
+                .get_vout()
+                .iter()
+                .position(|out| out.get_script_pubkey().as_htlc().as_ref() == Some(&contract))
+                .unwrap();
+            let outpoint = OutPoint::new(transaction.get_id(), vout);
 
-            if mine == MINE_TRUE {
+            submit_transaction(&utxo_set, transaction, from.as_str(), mine);
+            println!("Contract: {}", contract);
+            println!("Contract output: {}", outpoint);
+            if let Some(secret) = secret {
+                println!("Secret: {}", HEXLOWER.encode(secret.as_slice()));
+            }
+            println!(
+                "Secret hash: {}",
+                HEXLOWER.encode(contract.get_secret_hash())
+            );
+            println!("Refundable from: {}", describe_lock_time(lock_time));
+        }
+        Command::RedeemSwap {
+            contract,
+            secret,
+            to,
+            fee,
+            mine,
+        } => {
+            let secret = HEXLOWER_PERMISSIVE
+                .decode(secret.trim().as_bytes())
+                .unwrap_or_else(|e| panic!("ERROR: invalid secret: {}", e));
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let (output, htlc) = find_contract(&utxo_set, &contract);
+            let recipient = convert_address(htlc.get_recipient());
+            let to = to.unwrap_or_else(|| recipient.clone());
+            if !validate_destination(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            let transaction = Transaction::new_htlc_spend(
+                &output,
+                Some(secret.as_slice()),
+                to.as_str(),
+                fee,
+                &utxo_set,
+                &Wallets::new(),
+                utils::base58_decode,
+                utils::sha256_digest,
+                utils::ecdsa_p256_sha256_sign_digest,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let txid_hex = HEXLOWER.encode(transaction.get_id());
+            submit_transaction(&utxo_set, transaction, recipient.as_str(), mine);
+            println!("Redeemed {} in {}", contract, txid_hex);
+        }
+        Command::RefundSwap {
+            contract,
+            to,
+            fee,
+            mine,
+        } => {
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let (output, htlc) = find_contract(&utxo_set, &contract);
+            let refunder = convert_address(htlc.get_refund());
+            let to = to.unwrap_or_else(|| refunder.clone());
+            if !validate_destination(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            let transaction = Transaction::new_htlc_spend(
+                &output,
+                None,
+                to.as_str(),
+                fee,
+                &utxo_set,
+                &Wallets::new(),
+                utils::base58_decode,
+                utils::sha256_digest,
+                utils::ecdsa_p256_sha256_sign_digest,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            if !transaction.locks_satisfied(utxo_set.get_blockchain(), &[]) {
+                panic!(
+                    "ERROR: The contract can't be refunded before {}",
+                    describe_lock_time(htlc.get_lock_time())
+                )
+            }
+            let txid_hex = HEXLOWER.encode(transaction.get_id());
+            submit_transaction(&utxo_set, transaction, refunder.as_str(), mine);
+            println!("Refunded {} in {}", contract, txid_hex);
+        }
+        Command::AuditSwap { contract } => {
+            let blockchain = Blockchain::new_blockchain();
+            let (tx, height) = blockchain
+                .find_transaction_with_height(contract.get_txid())
+                .unwrap_or_else(|| panic!("ERROR: contract {} is not on the chain", contract));
+            let output = tx
+                .get_vout()
+                .get(contract.get_vout())
+                .unwrap_or_else(|| panic!("ERROR: contract {} is not on the chain", contract));
+            let htlc = output
+                .get_script_pubkey()
+                .as_htlc()
+                .unwrap_or_else(|| panic!("ERROR: {} is not a swap contract", contract));
+            println!("Contract: {}", htlc);
+            println!("Value: {}", output.get_value());
+            println!("Recipient: {}", convert_address(htlc.get_recipient()));
+            println!("Refund to: {}", convert_address(htlc.get_refund()));
+            println!("Secret hash: {}", HEXLOWER.encode(htlc.get_secret_hash()));
+            println!(
+                "Refundable from: {}",
+                describe_lock_time(htlc.get_lock_time())
+            );
+            println!(
+                "Confirmations: {}",
+                blockchain.get_best_height() - height + 1
+            );
+            let spender =
+                blockchain.find_spending_transaction(contract.get_txid(), contract.get_vout());
+            match spender {
+                None => println!("Status: unspent"),
+                Some(spender) => {
+                    let secret = spender
+                        .get_vin()
+                        .iter()
+                        .find(|vin| {
+                            vin.get_txid() == contract.get_txid()
+                                && vin.get_vout() == contract.get_vout()
+                        })
+                        .and_then(|vin| htlc.find_secret(vin.get_script_sig()));
+                    let txid_hex = HEXLOWER.encode(spender.get_id());
+                    match secret {
+                        Some(secret) => {
+                            println!("Status: redeemed in {}", txid_hex);
+                            println!("Secret: {}", HEXLOWER.encode(secret.as_slice()));
+                        }
+                        None => println!("Status: refunded in {}", txid_hex),
+                    }
+                }
+            }
+        }
+        Command::Anchor {
+            data,
+            from,
+            fee,
+            mine,
+        } => {
+            let digest = anchor_digest(data.as_str());
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let from = from.unwrap_or_else(|| {
+                let wallets = Wallets::new();
+                wallets
+                    .get_addresses()
+                    .into_iter()
+                    .filter(|address| !wallets.is_watch_only(address))
+                    .map(|address| (address_balance(&utxo_set, address.as_str()), address))
+                    .filter(|(balance, _)| *balance > 0)
+                    .max()
+                    .map(|(_, address)| address)
+                    .unwrap_or_else(|| panic!("ERROR: No wallet address can pay the fee"))
+            });
+            let transaction = Transaction::new_data_transaction(
+                from.as_str(),
+                digest.as_slice(),
+                fee,
+                &CoinControl::new(CoinSelectionStrategy::default(), vec![]),
+                &utxo_set,
+                utils::base58_decode,
+                utils::sha256_digest,
+                utils::ecdsa_p256_sha256_sign_digest,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let txid_hex = HEXLOWER.encode(transaction.get_id());
+            submit_transaction(&utxo_set, transaction, from.as_str(), mine);
+            println!(
+                "Anchored {} in {}",
+                HEXLOWER.encode(digest.as_slice()),
+                txid_hex
+            );
+        }
+        Command::FindAnchor { data } => {
+            let digest = anchor_digest(data.as_str());
+            let digest_hex = HEXLOWER.encode(digest.as_slice());
+            match Blockchain::new_blockchain().find_data(digest.as_slice()) {
+                Some((block, transaction)) => {
+                    println!("Anchored {}", digest_hex);
+                    println!("Block: {}", block.get_hash());
+                    println!("Height: {}", block.get_height());
+                    println!("Timestamp: {}", block.get_timestamp());
+                    println!("Transaction: {}", HEXLOWER.encode(transaction.get_id()));
+                }
+                None => {
+                    println!("{} is not anchored", digest_hex);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::IssueAsset {
+            from,
+            name,
+            supply,
+            to,
+            fee,
+            mine,
+        } => {
+            if !validate_address(from.as_str()) {
+                panic!("ERROR: Sender address is not valid")
+            }
+            let to = to.unwrap_or_else(|| from.clone());
+            if !validate_destination(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            let issuance = AssetIssuance::new(name.as_str(), supply)
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let transaction = Transaction::new_issuance_transaction(
+                from.as_str(),
+                issuance,
+                to.as_str(),
+                fee,
+                &CoinControl::new(CoinSelectionStrategy::default(), vec![]),
+                &utxo_set,
+                utils::base58_decode,
+                utils::sha256_digest,
+                utils::ecdsa_p256_sha256_sign_digest,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let asset = transaction.get_issued_asset(utils::sha256_digest).unwrap();
+            submit_transaction(&utxo_set, transaction, from.as_str(), mine);
+            println!("Asset: {}", HEXLOWER.encode(asset.as_slice()));
+            println!("Issued {} {} to {}", supply, name, to);
+        }
+        Command::MintNft {
+            from,
+            metadata,
+            to,
+            fee,
+            mine,
+        } => {
+            if !validate_address(from.as_str()) {
+                panic!("ERROR: Sender address is not valid")
+            }
+            let to = to.unwrap_or_else(|| from.clone());
+            if !validate_destination(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            let metadata_hash = anchor_digest(metadata.as_str());
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let transaction = Transaction::new_mint_transaction(
+                from.as_str(),
+                metadata_hash.as_slice(),
+                to.as_str(),
+                fee,
+                &CoinControl::new(CoinSelectionStrategy::default(), vec![]),
+                &utxo_set,
+                utils::base58_decode,
+                utils::sha256_digest,
+                utils::ecdsa_p256_sha256_sign_digest,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let token = transaction
+                .get_vout()
+                .iter()
+                .find_map(|out| out.get_nft())
+                .unwrap()
+                .clone();
+            submit_transaction(&utxo_set, transaction, from.as_str(), mine);
+            println!("Token: {}", HEXLOWER.encode(token.get_token_id()));
+            println!(
+                "Metadata hash: {}",
+                HEXLOWER.encode(token.get_metadata_hash())
+            );
+        }
+        Command::SendNft {
+            from,
+            to,
+            token,
+            fee,
+            mine,
+        } => {
+            if !validate_destination(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            move_nft(from.as_str(), token.as_str(), Some(to.as_str()), fee, mine);
+            println!("Success!")
+        }
+        Command::BurnNft {
+            from,
+            token,
+            fee,
+            mine,
+        } => {
+            move_nft(from.as_str(), token.as_str(), None, fee, mine);
+            println!("Burned {}", token);
+        }
+        Command::ListNfts { address } => {
+            let addresses = match address {
+                Some(address) => {
+                    if !validate_destination(address.as_str()) {
+                        panic!("ERROR: Address is not valid")
+                    }
+                    vec![address]
+                }
+                None => Wallets::new().get_addresses(),
+            };
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            for address in addresses {
+                let pub_key_hash = destination_hash(address.as_str(), utils::base58_decode)
+                    .unwrap_or_else(|e| panic!("ERROR: {}", e));
+                for utxo in utxo_set.find_nfts(pub_key_hash.as_slice()) {
+                    let token = utxo.get_output().get_nft().unwrap();
+                    println!(
+                        "{} metadata {} held by {} at {}",
+                        HEXLOWER.encode(token.get_token_id()),
+                        HEXLOWER.encode(token.get_metadata_hash()),
+                        address,
+                        utxo.get_outpoint()
+                    );
+                }
+            }
+        }
+        Command::TraceNft { token } => {
+            let token_id = decode_token_id(token.as_str());
+            let blockchain = Blockchain::new_blockchain();
+            let history = blockchain.find_nft_history(token_id.as_slice());
+            if history.is_empty() {
+                println!("{} was never minted", token);
+                std::process::exit(1);
+            }
+            for (idx, (transaction, height)) in history.iter().rev().enumerate() {
+                let output = transaction
+                    .get_vout()
+                    .iter()
+                    .find(|out| {
+                        out.get_nft()
+                            .is_some_and(|nft| nft.get_token_id() == token_id.as_slice())
+                    })
+                    .unwrap();
+                let txid_hex = HEXLOWER.encode(transaction.get_id());
+                if idx == 0 {
+                    println!(
+                        "Metadata hash: {}",
+                        HEXLOWER.encode(output.get_nft().unwrap().get_metadata_hash())
+                    );
+                }
+                if output.is_unspendable() {
+                    println!("Burned in {} at height {}", txid_hex, height);
+                } else if idx == 0 {
+                    println!(
+                        "Minted in {} at height {} to {}",
+                        txid_hex,
+                        height,
+                        output.get_destination()
+                    );
+                } else {
+                    println!(
+                        "Transferred in {} at height {} to {}",
+                        txid_hex,
+                        height,
+                        output.get_destination()
+                    );
+                }
+            }
+        }
+        Command::BroadcastPsbt { file, mine } => {
+            let psbt = read_psbt(file.as_str());
+            let reward_to = psbt
+                .get_prev_outputs()
+                .iter()
+                .find_map(|out| out.get_script_pubkey().as_p2pkh().map(convert_address))
+                .or_else(|| Wallets::new().get_addresses().into_iter().next())
+                .unwrap_or_default();
+            let transaction = psbt
+                .finalize(utils::sha256_digest)
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            if !transaction.verify(
+                utxo_set.get_blockchain(),
+                utils::sha256_digest,
+                utils::ecdsa_p256_sha256_sign_verify,
+            ) {
+                panic!("ERROR: Transaction signatures are not valid")
+            }
+            if !transaction.locks_satisfied(utxo_set.get_blockchain(), &[]) {
+                panic!("ERROR: Transaction is still timelocked")
+            }
+            if !transaction.coinbase_spends_mature(utxo_set.get_blockchain(), &[]) {
+                panic!("ERROR: Transaction spends an immature coinbase")
+            }
+            let txid_hex = HEXLOWER.encode(transaction.get_id());
+            submit_transaction(&utxo_set, transaction, reward_to.as_str(), mine);
+            println!("Sent transaction {}", txid_hex);
+        }
+        Command::Generate { address, blocks } => {
+            if !validate_address(address.as_str()) {
+                panic!("ERROR: Address is not valid")
+            }
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            for _ in 0..blocks {
                 let coinbase_tx = Transaction::new_coinbase_tx(
                     utils::sha256_digest,
                     utils::base58_decode,
-                    from.as_str(),
+                    address.as_str(),
+                    0,
+                    utxo_set.get_blockchain().get_best_height() + 1,
+                    &[],
                 )
                 .unwrap();
-
-                let block = blockchain.mine_block(
-                    &vec![transaction, coinbase_tx],
+                let block = utxo_set.get_blockchain().mine_block(
+                    &[coinbase_tx],
                     utils::sha256_digest,
                     utils::ecdsa_p256_sha256_sign_verify,
                     utils::current_timestamp,
                 );
-
                 utxo_set.update(&block);
+            }
+            println!(
+                "Mined {} blocks, height {}",
+                blocks,
+                utxo_set.get_blockchain().get_best_height()
+            );
+        }
+        Command::EstimateFee { target_blocks } => {
+            let blockchain = Blockchain::new_blockchain();
+            let estimator = FeeEstimator::new(blockchain);
+            match estimator.estimate_fee(target_blocks) {
+                Some(fee_rate) => println!(
+                    "Estimated fee rate for {} blocks: {} per 1000 bytes",
+                    target_blocks, fee_rate
+                ),
+                None => println!(
+                    "Insufficient data, minimum fee rate is {} per 1000 bytes",
+                    fee_estimator::MIN_FEE_RATE
+                ),
+            }
+        }
+        Command::GetBlockTemplate {
+            mining_address,
+            extra_nonce,
+        } => {
+            let extra_nonce = extra_nonce
+                .map(|extra_nonce| {
+                    HEXLOWER_PERMISSIVE
+                        .decode(extra_nonce.as_bytes())
+                        .unwrap_or_else(|e| panic!("ERROR: Invalid extra nonce: {}", e))
+                })
+                .unwrap_or_default();
+            let node_addr = GLOBAL_CONFIG.get_node_addr();
+            let request = Package::GetBlockTemplate {
+                addr_from: node_addr.clone(),
+                mining_address,
+                extra_nonce,
+            };
+            let response = send_request(node_addr.as_str(), &request).unwrap();
+            println!("{}", serde_json::to_string_pretty(&response).unwrap());
+        }
+        Command::SubmitBlock {
+            template_id,
+            timestamp,
+            nonce,
+        } => {
+            let node_addr = GLOBAL_CONFIG.get_node_addr();
+            let request = Package::SubmitBlock {
+                addr_from: node_addr.clone(),
+                template_id,
+                timestamp,
+                nonce,
+            };
+            let response = send_request(node_addr.as_str(), &request).unwrap();
+            println!("{}", serde_json::to_string_pretty(&response).unwrap());
+        }
+        Command::EncryptWallet { passphrase } => {
+            let mut wallets = Wallets::new();
+            wallets
+                .encrypt(passphrase.as_str())
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            println!("Wallet encrypted, use walletpassphrase to unlock it for signing");
+        }
+        Command::WalletPassphrase {
+            passphrase,
+            timeout,
+        } => {
+            let mut wallets = Wallets::new();
+            let token = wallets
+                .unlock(passphrase.as_str(), timeout)
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            println!("Wallet unlocked for {} seconds, set this in the environment of the commands that sign:", timeout);
+            println!("WALLET_SESSION={}", token);
+        }
+        Command::WalletLock => {
+            let mut wallets = Wallets::new();
+            wallets.lock();
+            println!("Wallet locked");
+        }
+        Command::DumpPrivKey { address, pem } => {
+            let wallets = Wallets::new();
+            let wallet = wallets
+                .get_signing_wallet(address.as_str())
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            if pem {
+                println!("{}", encode_private_key_pem(wallet.get_pkcs8()));
             } else {
-                send_tx(CENTERAL_NODE, &transaction);
+                println!("{}", encode_private_key(wallet.get_pkcs8()));
             }
-            println!("Success!")
+        }
+        Command::SignMessage { address, message } => {
+            let wallets = Wallets::new();
+            let wallet = wallets
+                .get_signing_wallet(address.as_str())
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            println!(
+                "{}",
+                wallet.sign_message(message.as_str(), utils::ecdsa_p256_sha256_sign_digest)
+            );
+        }
+        Command::VerifyMessage {
+            address,
+            signature,
+            message,
+        } => {
+            let valid = verify_message(
+                address.as_str(),
+                signature.as_str(),
+                message.as_str(),
+                utils::ecdsa_p256_sha256_sign_verify,
+            )
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            if valid {
+                println!("Signature is valid");
+            } else {
+                println!("Signature is NOT valid");
+                std::process::exit(1);
+            }
+        }
+        Command::ImportPrivKey { key, no_rescan } => {
+            let mut wallets = Wallets::new();
+            let key = std::fs::read_to_string(key.as_str()).unwrap_or(key);
+            let address = decode_private_key(key.as_str())
+                .and_then(|pkcs8| wallets.import_private_key(pkcs8))
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            println!("Imported address: {}", address);
+            if !no_rescan {
+                rescan_wallet(&wallets);
+            }
+        }
+        Command::ImportAddress { address, no_rescan } => {
+            let mut wallets = Wallets::new();
+            let address = wallets
+                .import_watch_only(address.as_str())
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            println!("Watching address: {}", address);
+            if !no_rescan {
+                rescan_wallet(&wallets);
+            }
+        }
+        Command::RescanWallet => {
+            let wallets = Wallets::new();
+            rescan_wallet(&wallets);
+        }
+        Command::ListTransactions { address, count } => {
+            let wallets = Wallets::new();
+            let mut addresses = wallets.get_addresses();
+            addresses.extend(wallets.get_watch_only_addresses());
+            if let Some(address) = address {
+                if !addresses.contains(&address) {
+                    panic!("ERROR: address {} is not in the wallet", address)
+                }
+                addresses = vec![address];
+            }
+            let blockchain = Blockchain::new_blockchain();
+            let ledger = WalletLedger::scan(&blockchain, addresses.as_slice());
+            for entry in ledger.get_entries().iter().rev().take(count) {
+                let fee = entry
+                    .get_fee()
+                    .map(|fee| format!(", fee {}", fee))
+                    .unwrap_or_default();
+                println!(
+                    "- {} {} {}{}{}",
+                    entry.get_txid(),
+                    entry.get_category(),
+                    entry.get_amount(),
+                    fee,
+                    label_suffix(&wallets, entry.get_txid())
+                );
+                println!(
+                    "-- Height {}, {} confirmations, timestamp {}",
+                    entry.get_height(),
+                    entry.get_confirmations(),
+                    entry.get_timestamp()
+                );
+                for address in entry.get_addresses() {
+                    println!(
+                        "-- Wallet address {}{}",
+                        address,
+                        label_suffix(&wallets, address.as_str())
+                    );
+                }
+                for address in entry.get_counterparties() {
+                    println!(
+                        "-- Counterparty {}{}",
+                        address,
+                        label_suffix(&wallets, address.as_str())
+                    );
+                }
+            }
+        }
+        Command::SetLabel { target, label } => {
+            let mut wallets = Wallets::new();
+            wallets
+                .set_label(target.as_str(), label.as_str())
+                .unwrap_or_else(|e| panic!("ERROR: {}", e));
+            println!("Done!");
+        }
+        Command::ListUnspent { address } => {
+            let wallets = Wallets::new();
+            let mut addresses = wallets.get_addresses();
+            addresses.extend(wallets.get_watch_only_addresses());
+            if let Some(address) = address {
+                addresses = vec![address];
+            }
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+            let next_height = utxo_set.get_blockchain().get_best_height() + 1;
+            for address in addresses {
+                let payload = utils::base58_decode(address.as_str());
+                let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+                for utxo in utxo_set.find_unspent_outputs(pub_key_hash) {
+                    let outpoint = utxo.get_outpoint();
+                    let frozen = if wallets.is_frozen(&outpoint) {
+                        " (frozen)"
+                    } else {
+                        ""
+                    };
+                    let immature =
+                        if utxo.is_mature(next_height, GLOBAL_CONFIG.get_coinbase_maturity()) {
+                            ""
+                        } else {
+                            " (immature)"
+                        };
+                    println!(
+                        "{} value = {}, height = {}, address = {}{}{}",
+                        outpoint,
+                        utxo.get_value(),
+                        utxo.get_height(),
+                        address,
+                        frozen,
+                        immature
+                    );
+                }
+            }
+        }
+        Command::FreezeUtxo { outpoint } => {
+            let mut wallets = Wallets::new();
+            wallets.freeze_output(outpoint);
+            println!("Done!");
+        }
+        Command::UnfreezeUtxo { outpoint } => {
+            let mut wallets = Wallets::new();
+            if !wallets.unfreeze_output(&outpoint) {
+                panic!("ERROR: {} is not frozen", outpoint)
+            }
+            println!("Done!");
         }
         Command::Printchain => {
             let mut block_iterator = Blockchain::new_blockchain().iterator();
@@ -170,12 +1707,29 @@ fn main() {
                 for tx in block.get_transactions() {
                     let cur_txid_hex = HEXLOWER.encode(tx.get_id());
                     println!("- Transaction txid_hex: {}", cur_txid_hex);
+                    if tx.get_lock_time() > 0 {
+                        println!("-- Locked until {}", describe_lock_time(tx.get_lock_time()));
+                    }
+                    if let Some(issuance) = tx.get_issuance() {
+                        println!(
+                            "-- Issues {} {}, asset {}",
+                            issuance.get_supply(),
+                            issuance.get_name(),
+                            HEXLOWER.encode(
+                                tx.get_issued_asset(utils::sha256_digest)
+                                    .unwrap()
+                                    .as_slice()
+                            )
+                        );
+                    }
 
                     if tx.is_coinbase() == false {
                         for input in tx.get_vin() {
                             let txid_hex = HEXLOWER.encode(input.get_txid());
-                            let pub_key_hash = hash_pub_key(input.get_pub_key());
-                            let address = convert_address(pub_key_hash.as_slice());
+                            let address = match input.get_script_sig().get_unlock_pub_key() {
+                                Some(pub_key) => convert_address(hash_pub_key(pub_key).as_slice()),
+                                None => format!("script {}", input.get_script_sig()),
+                            };
                             println!(
                                 "-- Input txid = {}, vout = {}, from = {}",
                                 txid_hex,
@@ -185,9 +1739,25 @@ fn main() {
                         }
                     }
                     for output in tx.get_vout() {
-                        let pub_key_hash = output.get_pub_key_hash();
-                        let address = convert_address(pub_key_hash);
-                        println!("-- Output value = {}, to = {}", output.get_value(), address,)
+                        let address = output.get_destination();
+                        match output.get_asset() {
+                            Some(asset) => println!(
+                                "-- Output value = {}, asset = {}, to = {}",
+                                output.get_value(),
+                                HEXLOWER.encode(asset),
+                                address,
+                            ),
+                            None if output.get_nft().is_some() => println!(
+                                "-- Output token = {}, to = {}",
+                                HEXLOWER.encode(output.get_nft().unwrap().get_token_id()),
+                                address,
+                            ),
+                            None => println!(
+                                "-- Output value = {}, to = {}",
+                                output.get_value(),
+                                address,
+                            ),
+                        }
                     }
                 }
                 println!()
@@ -214,3 +1784,218 @@ fn main() {
         }
     }
 }
+
+// mines the transaction right away or hands it to the central node
+fn submit_transaction(utxo_set: &UTXOSet, transaction: Transaction, reward_to: &str, mine: bool) {
+    if !mine {
+        send_tx(CENTERAL_NODE, &transaction);
+        return;
+    }
+    let coinbase_tx = Transaction::new_coinbase_tx(
+        utils::sha256_digest,
+        utils::base58_decode,
+        reward_to,
+        0,
+        utxo_set.get_blockchain().get_best_height() + 1,
+        &[],
+    )
+    .unwrap();
+    let block = utxo_set.get_blockchain().mine_block(
+        &[transaction, coinbase_tx],
+        utils::sha256_digest,
+        utils::ecdsa_p256_sha256_sign_verify,
+        utils::current_timestamp,
+    );
+    utxo_set.update(&block);
+}
+
+// the SHA-256 of a file, or the digest itself when given as hex
+fn anchor_digest(data: &str) -> Vec<u8> {
+    if std::path::Path::new(data).is_file() {
+        let content =
+            std::fs::read(data).unwrap_or_else(|e| panic!("ERROR: unable to read {}: {}", data, e));
+        return utils::sha256_digest(content.as_slice());
+    }
+    HEXLOWER_PERMISSIVE
+        .decode(data.trim().as_bytes())
+        .unwrap_or_else(|_| panic!("ERROR: {} is neither a file nor a hex digest", data))
+}
+
+// the unspent contract output and its terms
+fn find_contract(utxo_set: &UTXOSet, contract: &OutPoint) -> (UnspentOutput, HtlcContract) {
+    let output = utxo_set
+        .get_unspent_output(contract)
+        .unwrap_or_else(|| panic!("ERROR: contract {} is spent or unknown", contract));
+    let htlc = output
+        .get_output()
+        .get_script_pubkey()
+        .as_htlc()
+        .unwrap_or_else(|| panic!("ERROR: {} is not a swap contract", contract));
+    (output, htlc)
+}
+
+fn describe_lock_time(lock_time: u32) -> String {
+    if (lock_time as i64) < LOCK_TIME_THRESHOLD {
+        format!("height {}", lock_time)
+    } else {
+        format!("unix time {}", lock_time)
+    }
+}
+
+fn parse_payment(payment: &str, separator: char) -> (String, i32) {
+    // multi(...) descriptors contain commas, the amount never does
+    let (address, amount) = payment.rsplit_once(separator).unwrap_or_else(|| {
+        panic!(
+            "ERROR: payment {} is not address{}amount",
+            payment, separator
+        )
+    });
+    let amount = amount
+        .trim()
+        .parse()
+        .unwrap_or_else(|_| panic!("ERROR: invalid amount in payment {}", payment));
+    (String::from(address.trim()), amount)
+}
+
+#[derive(Deserialize)]
+struct PaymentRecord {
+    address: String,
+    amount: i32,
+}
+
+fn read_payments_file(path: &str) -> Vec<(String, i32)> {
+    let content = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("ERROR: unable to read {}: {}", path, e));
+    if path.ends_with(".json") {
+        let records: Vec<PaymentRecord> = serde_json::from_str(content.as_str())
+            .unwrap_or_else(|e| panic!("ERROR: invalid payments file {}: {}", path, e));
+        return records
+            .into_iter()
+            .map(|record| (record.address, record.amount))
+            .collect();
+    }
+    let mut payments = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // optional header row
+        if number == 0 && line.eq_ignore_ascii_case("address,amount") {
+            continue;
+        }
+        payments.push(parse_payment(line, ','));
+    }
+    payments
+}
+
+fn read_psbt(path: &str) -> PartiallySignedTransaction {
+    let data = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("ERROR: unable to read {}: {}", path, e));
+    PartiallySignedTransaction::from_hex(data.as_str()).unwrap_or_else(|e| panic!("ERROR: {}", e))
+}
+
+fn write_psbt(path: &str, psbt: &PartiallySignedTransaction) {
+    std::fs::write(path, psbt.to_hex())
+        .unwrap_or_else(|e| panic!("ERROR: unable to write {}: {}", path, e));
+}
+
+fn address_balance(utxo_set: &UTXOSet, address: &str) -> i32 {
+    let pub_key_hash =
+        destination_hash(address, utils::base58_decode).unwrap_or_else(|e| panic!("ERROR: {}", e));
+    utxo_set
+        .find_spendable_outputs(pub_key_hash.as_slice())
+        .iter()
+        .map(|utxo| utxo.get_value())
+        .sum()
+}
+
+fn address_immature_balance(utxo_set: &UTXOSet, address: &str) -> i32 {
+    let pub_key_hash =
+        destination_hash(address, utils::base58_decode).unwrap_or_else(|e| panic!("ERROR: {}", e));
+    utxo_set.get_immature_balance(pub_key_hash.as_slice())
+}
+
+fn decode_token_id(token: &str) -> Vec<u8> {
+    HEXLOWER_PERMISSIVE
+        .decode(token.as_bytes())
+        .unwrap_or_else(|e| panic!("ERROR: Invalid token id: {}", e))
+}
+
+// transfers the token to `to`, or burns it when `to` is None
+fn move_nft(from: &str, token: &str, to: Option<&str>, fee: Option<i32>, mine: bool) {
+    if !validate_address(from) {
+        panic!("ERROR: Sender address is not valid")
+    }
+    let token_id = decode_token_id(token);
+    let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+    let transaction = Transaction::new_nft_transaction(
+        from,
+        token_id.as_slice(),
+        to,
+        fee,
+        &CoinControl::new(CoinSelectionStrategy::default(), vec![]),
+        &utxo_set,
+        utils::base58_decode,
+        utils::sha256_digest,
+        utils::ecdsa_p256_sha256_sign_digest,
+    )
+    .unwrap_or_else(|e| panic!("ERROR: {}", e));
+    submit_transaction(&utxo_set, transaction, from, mine);
+}
+
+// per-asset holdings of `addresses`, named after their issuance
+fn print_asset_balances(utxo_set: &UTXOSet, addresses: &[String]) {
+    let mut balances: BTreeMap<Vec<u8>, i32> = BTreeMap::new();
+    for address in addresses {
+        let pub_key_hash = destination_hash(address, utils::base58_decode)
+            .unwrap_or_else(|e| panic!("ERROR: {}", e));
+        for (asset, value) in utxo_set.find_asset_balances(pub_key_hash.as_slice()) {
+            *balances.entry(asset).or_insert(0) += value;
+        }
+    }
+    for (asset, value) in balances {
+        let name = utxo_set
+            .get_blockchain()
+            .find_issuance(asset.as_slice(), utils::sha256_digest)
+            .map(|issuance| issuance.get_name().to_string())
+            .unwrap_or_default();
+        println!(
+            "Asset {} ({}): {}",
+            name,
+            HEXLOWER.encode(asset.as_slice()),
+            value
+        );
+    }
+}
+
+fn label_suffix(wallets: &Wallets, target: &str) -> String {
+    wallets
+        .get_label(target)
+        .map(|label| format!(" [{}]", label))
+        .unwrap_or_default()
+}
+
+// rebuilds the UTXO set from the chain so imported keys see all their outputs
+fn rescan_wallet(wallets: &Wallets) {
+    if !Blockchain::exists() {
+        println!("No blockchain found, skipping rescan");
+        return;
+    }
+    let utxo_set = UTXOSet::new(Blockchain::new_blockchain());
+    utxo_set.reindex();
+    let mut addresses = wallets.get_addresses();
+    addresses.extend(wallets.get_watch_only_addresses());
+    for address in addresses {
+        let payload = utils::base58_decode(address.as_str());
+        let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+        let utxos = utxo_set.find_utxo(pub_key_hash);
+        let balance: i32 = utxos.iter().map(|utxo| utxo.get_value()).sum();
+        println!(
+            "{}: {} unspent outputs, balance {}",
+            address,
+            utxos.len(),
+            balance
+        );
+    }
+}
@@ -1,11 +1,61 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
 
 use data_encoding::HEXLOWER;
 
+use crate::blockchain::BlockError;
 use crate::transaction::Transaction;
 
+#[derive(Clone)]
+pub struct MemoryPoolEntry {
+    tx: Transaction,
+    fee: i32,
+    size: usize,
+    time: i64,
+}
+
+impl MemoryPoolEntry {
+    pub fn new(tx: Transaction, fee: i32, time: i64) -> MemoryPoolEntry {
+        let size = tx.serialize().map(|bytes| bytes.len()).unwrap_or_default();
+        MemoryPoolEntry {
+            tx,
+            fee,
+            size,
+            time,
+        }
+    }
+    pub fn get_tx(&self) -> &Transaction {
+        &self.tx
+    }
+    pub fn get_txid_hex(&self) -> String {
+        HEXLOWER.encode(self.tx.get_id())
+    }
+    pub fn get_fee(&self) -> i32 {
+        self.fee
+    }
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+    pub fn get_time(&self) -> i64 {
+        self.time
+    }
+    // txids of the in-pool transactions whose outputs this entry spends
+    pub fn parents(&self, pool: &HashMap<String, MemoryPoolEntry>) -> Vec<String> {
+        let mut parents = vec![];
+        for vin in self.tx.get_vin() {
+            let txid_hex = HEXLOWER.encode(vin.get_txid());
+            if pool.contains_key(txid_hex.as_str()) && !parents.contains(&txid_hex) {
+                parents.push(txid_hex);
+            }
+        }
+        parents
+    }
+}
+
 pub struct MemoryPool {
-    inner: RwLock<HashMap<String, Transaction>>,
+    inner: RwLock<HashMap<String, MemoryPoolEntry>>,
 }
 
 impl MemoryPool {
@@ -17,32 +67,103 @@ impl MemoryPool {
     pub fn contains(&self, txid_hex: &str) -> bool {
         self.inner.read().unwrap().contains_key(txid_hex)
     }
-    pub fn add(&self, tx: Transaction) {
+    // fails when another pool transaction already spends one of its inputs
+    pub fn add(&self, tx: Transaction, fee: i32) -> Result<(), BlockError> {
         let txid_hex = HEXLOWER.encode(tx.get_id());
-        self.inner.write().unwrap().insert(txid_hex, tx);
+        let mut inner = self.inner.write().unwrap();
+        let spent: HashSet<(&[u8], usize)> = tx
+            .get_vin()
+            .iter()
+            .map(|vin| (vin.get_txid(), vin.get_vout()))
+            .collect();
+        let conflict = inner
+            .values()
+            .filter(|entry| entry.tx.get_id() != tx.get_id())
+            .flat_map(|entry| entry.tx.get_vin())
+            .any(|vin| spent.contains(&(vin.get_txid(), vin.get_vout())));
+        if conflict {
+            return Err(BlockError::DoubleSpend(txid_hex));
+        }
+        let entry = MemoryPoolEntry::new(tx, fee, crate::utils::current_timestamp());
+        inner.insert(txid_hex, entry);
+        Ok(())
     }
     pub fn get(&self, txid_hex: &str) -> Option<Transaction> {
-        if let Some(tx) = self.inner.read().unwrap().get(txid_hex) {
-            return Some(tx.clone());
+        if let Some(entry) = self.inner.read().unwrap().get(txid_hex) {
+            return Some(entry.tx.clone());
         }
         None
     }
+    pub fn get_entry(&self, txid_hex: &str) -> Option<MemoryPoolEntry> {
+        self.inner.read().unwrap().get(txid_hex).cloned()
+    }
     pub fn remove(&self, txid_hex: &str) {
         let mut inner = self.inner.write().unwrap();
         inner.remove(txid_hex);
     }
+    // removes a transaction and everything in the pool spending from it
+    pub fn evict(&self, txid_hex: &str) {
+        let mut inner = self.inner.write().unwrap();
+        for descendant in descendants_of(&inner, txid_hex) {
+            inner.remove(descendant.as_str());
+        }
+        inner.remove(txid_hex);
+    }
     pub fn get_all(&self) -> Vec<Transaction> {
         let inner = self.inner.read().unwrap();
         let mut txs = vec![];
         for (_, v) in inner.iter() {
-            txs.push(v.clone());
+            txs.push(v.tx.clone());
         }
         return txs;
     }
+    pub fn get_entries(&self) -> HashMap<String, MemoryPoolEntry> {
+        self.inner.read().unwrap().clone()
+    }
+    pub fn get_ancestors(&self, txid_hex: &str) -> HashSet<String> {
+        let inner = self.inner.read().unwrap();
+        ancestors_of(&inner, txid_hex)
+    }
+    pub fn get_descendants(&self, txid_hex: &str) -> HashSet<String> {
+        let inner = self.inner.read().unwrap();
+        descendants_of(&inner, txid_hex)
+    }
     pub fn len(&self) -> usize {
         self.inner.read().unwrap().len()
     }
 }
+pub fn ancestors_of(pool: &HashMap<String, MemoryPoolEntry>, txid_hex: &str) -> HashSet<String> {
+    let mut ancestors = HashSet::new();
+    let mut stack = vec![txid_hex.to_string()];
+    while let Some(current) = stack.pop() {
+        if let Some(entry) = pool.get(current.as_str()) {
+            for parent in entry.parents(pool) {
+                if ancestors.insert(parent.clone()) {
+                    stack.push(parent);
+                }
+            }
+        }
+    }
+    ancestors
+}
+
+pub fn descendants_of(pool: &HashMap<String, MemoryPoolEntry>, txid_hex: &str) -> HashSet<String> {
+    let mut descendants = HashSet::new();
+    let mut stack = vec![txid_hex.to_string()];
+    while let Some(current) = stack.pop() {
+        for (child_txid, entry) in pool {
+            if descendants.contains(child_txid) {
+                continue;
+            }
+            if entry.parents(pool).contains(&current) {
+                descendants.insert(child_txid.clone());
+                stack.push(child_txid.clone());
+            }
+        }
+    }
+    descendants
+}
+
 pub struct BlockInTransit {
     inner: RwLock<Vec<Vec<u8>>>,
 }
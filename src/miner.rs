@@ -0,0 +1,140 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use crate::block_template::BlockTemplateBuilder;
+use crate::blockchain::Blockchain;
+use crate::config::GLOBAL_CONFIG;
+use crate::proof_of_work::{self, CancellationToken, MiningError};
+use crate::server::{connect_block, GLOBAL_MEMORY_POOL};
+use crate::transaction::Transaction;
+
+// how long the miner waits for transactions before mining an empty block
+const EMPTY_BLOCK_INTERVAL: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// minimum time a block is mined before new transactions replace its template
+const RESTART_INTERVAL: Duration = Duration::from_secs(2);
+
+// Background mining loop: builds a template from the memory pool, mines it on
+// the current tip and starts over whenever the tip or the pool changes.
+#[derive(Clone)]
+pub struct Miner {
+    blockchain: Blockchain,
+    current_job: Arc<Mutex<CancellationToken>>,
+    job_started: Arc<Mutex<Instant>>,
+    restart_pending: Arc<AtomicBool>,
+}
+
+impl Miner {
+    pub fn new(blockchain: Blockchain) -> Miner {
+        Miner {
+            blockchain,
+            current_job: Arc::new(Mutex::new(CancellationToken::new())),
+            job_started: Arc::new(Mutex::new(Instant::now())),
+            restart_pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn start(&self) -> JoinHandle<()> {
+        let miner = self.clone();
+        thread::spawn(move || miner.debounce_restarts());
+        let miner = self.clone();
+        thread::spawn(move || miner.run())
+    }
+
+    // asks for a new template; the block being mined is abandoned once it has
+    // been worked on for RESTART_INTERVAL, so a stream of transactions can't
+    // keep resetting proof of work
+    pub fn restart(&self) {
+        self.restart_pending.store(true, Ordering::SeqCst);
+    }
+
+    fn debounce_restarts(&self) {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if self.restart_pending.load(Ordering::SeqCst)
+                && self.job_started.lock().unwrap().elapsed() >= RESTART_INTERVAL
+            {
+                self.restart_pending.store(false, Ordering::SeqCst);
+                self.current_job.lock().unwrap().cancel();
+            }
+        }
+    }
+
+    fn new_job(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        // the new template already holds every pending transaction
+        self.restart_pending.store(false, Ordering::SeqCst);
+        *self.job_started.lock().unwrap() = Instant::now();
+        *self.current_job.lock().unwrap() = token.clone();
+        token
+    }
+
+    fn run(&self) {
+        let mining_address = GLOBAL_CONFIG
+            .get_mining_addr()
+            .expect("mining address is not set");
+        let mut last_block = Instant::now();
+        loop {
+            let token = self.new_job();
+            let template = BlockTemplateBuilder::default().build(&GLOBAL_MEMORY_POOL.get_entries());
+            if template.is_empty() && last_block.elapsed() < EMPTY_BLOCK_INTERVAL {
+                let tip_token = self.blockchain.watch_tip();
+                // nothing is being mined yet, so new transactions are picked up right away
+                while !token.is_cancelled()
+                    && !self.restart_pending.load(Ordering::SeqCst)
+                    && !tip_token.is_cancelled()
+                    && last_block.elapsed() < EMPTY_BLOCK_INTERVAL
+                {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                continue;
+            }
+
+            let height = self.blockchain.get_best_height() + 1;
+            let coinbase_tx = Transaction::new_coinbase_tx(
+                crate::utils::sha256_digest,
+                crate::utils::base58_decode,
+                mining_address.as_str(),
+                template.get_total_fees() as i32,
+                height,
+                &[],
+            )
+            .unwrap();
+            let mut txs = template.get_transactions().to_vec();
+            txs.push(coinbase_tx);
+            info!(
+                "Mining block with {} transactions, {} fees",
+                txs.len(),
+                template.get_total_fees()
+            );
+
+            let result = self.blockchain.try_mine_block(
+                &txs,
+                crate::utils::sha256_digest,
+                crate::utils::ecdsa_p256_sha256_sign_verify,
+                crate::utils::current_timestamp,
+                proof_of_work::default_threads(),
+                &token,
+            );
+            match result {
+                Ok(block) => {
+                    info!("New block {} is mined!", block.get_hash());
+                    connect_block(&self.blockchain, &block);
+                    last_block = Instant::now();
+                }
+                Err(MiningError::Cancelled) => info!("Mining restarted"),
+                Err(MiningError::Rejected(e)) => {
+                    error!("Mining failed: {}", e);
+                    match e.get_txid() {
+                        Some(txid_hex) => GLOBAL_MEMORY_POOL.evict(txid_hex),
+                        None => thread::sleep(POLL_INTERVAL),
+                    }
+                }
+            }
+        }
+    }
+}
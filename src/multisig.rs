@@ -0,0 +1,112 @@
+use std::fmt;
+use std::str::FromStr;
+
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use serde::{Deserialize, Serialize};
+
+use crate::script::Script;
+use crate::wallet::{convert_script_address, WalletError};
+
+pub const MAX_MULTISIG_KEYS: usize = 15;
+const DESCRIPTOR_PREFIX: &str = "multi(";
+
+// An m-of-n spending policy: any `required` of `pub_keys` must sign. Written
+// as multi(m,hex_key,...), which pays to the bare script, or paid to through
+// its pay-to-script-hash address.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MultisigPolicy {
+    required: usize,
+    pub_keys: Vec<Vec<u8>>,
+}
+
+impl MultisigPolicy {
+    pub fn new(required: usize, pub_keys: Vec<Vec<u8>>) -> Result<MultisigPolicy, WalletError> {
+        if pub_keys.is_empty() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(WalletError::InvalidPolicy(format!(
+                "a multisig policy needs 1 to {} keys",
+                MAX_MULTISIG_KEYS
+            )));
+        }
+        if required == 0 || required > pub_keys.len() {
+            return Err(WalletError::InvalidPolicy(format!(
+                "required signatures must be between 1 and {}",
+                pub_keys.len()
+            )));
+        }
+        for (idx, key) in pub_keys.iter().enumerate() {
+            if !crate::script::is_pub_key(key) {
+                return Err(WalletError::InvalidPolicy(format!(
+                    "{} is not a P-256 public key",
+                    HEXLOWER.encode(key.as_slice())
+                )));
+            }
+            if pub_keys[..idx].contains(key) {
+                return Err(WalletError::InvalidPolicy(String::from(
+                    "keys must be distinct",
+                )));
+            }
+        }
+        Ok(MultisigPolicy { required, pub_keys })
+    }
+
+    pub fn is_descriptor(destination: &str) -> bool {
+        destination.starts_with(DESCRIPTOR_PREFIX)
+    }
+
+    pub fn get_required(&self) -> usize {
+        self.required
+    }
+
+    pub fn get_pub_keys(&self) -> &[Vec<u8>] {
+        self.pub_keys.as_slice()
+    }
+
+    pub fn key_index(&self, pub_key: &[u8]) -> Option<usize> {
+        self.pub_keys
+            .iter()
+            .position(|key| key.as_slice() == pub_key)
+    }
+
+    // stands in for the pub_key_hash of outputs locked to the policy
+    pub fn hash(&self) -> Vec<u8> {
+        Script::multisig(self).hash()
+    }
+
+    // the pay-to-script-hash address, so payers need not know the keys
+    pub fn get_address(&self) -> String {
+        convert_script_address(self.hash().as_slice())
+    }
+}
+
+impl fmt::Display for MultisigPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", DESCRIPTOR_PREFIX, self.required)?;
+        for key in &self.pub_keys {
+            write!(f, ",{}", HEXLOWER.encode(key.as_slice()))?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl FromStr for MultisigPolicy {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: &str| WalletError::InvalidPolicy(format!("{}: {}", s, e));
+        let body = s
+            .trim()
+            .strip_prefix(DESCRIPTOR_PREFIX)
+            .and_then(|body| body.strip_suffix(')'))
+            .ok_or_else(|| invalid("expected multi(m,key,...)"))?;
+        let mut parts = body.split(',');
+        let required = parts
+            .next()
+            .and_then(|m| m.trim().parse().ok())
+            .ok_or_else(|| invalid("missing required signature count"))?;
+        let pub_keys = parts
+            .map(|key| HEXLOWER_PERMISSIVE.decode(key.trim().as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(e.to_string().as_str()))?;
+        MultisigPolicy::new(required, pub_keys)
+    }
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+use crate::block::HashFn;
+
+pub const METADATA_HASH_LEN: usize = 32;
+
+// A unique item held by a single output. The token id commits to the outpoint
+// spent by the first input of the minting transaction and to the metadata
+// hash, so no two mints can produce the same token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NonFungibleToken {
+    token_id: Vec<u8>,
+    metadata_hash: Vec<u8>,
+}
+
+impl NonFungibleToken {
+    // the token minted by a transaction whose first input spends output
+    // `vout` of `txid`
+    pub fn mint(
+        txid: &[u8],
+        vout: usize,
+        metadata_hash: &[u8],
+        hash_fn: HashFn,
+    ) -> NonFungibleToken {
+        let mut data = txid.to_vec();
+        data.extend((vout as u64).to_be_bytes());
+        data.extend(metadata_hash);
+        NonFungibleToken {
+            token_id: hash_fn(data.as_slice()),
+            metadata_hash: metadata_hash.to_vec(),
+        }
+    }
+
+    pub fn get_token_id(&self) -> &[u8] {
+        self.token_id.as_slice()
+    }
+
+    pub fn get_metadata_hash(&self) -> &[u8] {
+        self.metadata_hash.as_slice()
+    }
+}
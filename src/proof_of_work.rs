@@ -1,7 +1,15 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
 use data_encoding::HEXLOWER;
+use log::info;
 use num_bigint::{BigInt, Sign};
 
-use crate::block::{Block, Hash, HashFn, Nonce};
+use crate::block::{Block, Hash, HashFn, Nonce, TimestampProvider};
+use crate::blockchain::BlockError;
 
 pub struct ProofOfWork<'a> {
     block: &'a Block,
@@ -10,50 +18,226 @@ pub struct ProofOfWork<'a> {
 
 #[derive(Debug)]
 pub enum MiningError {
-    NonceExhausted,
+    Cancelled,
+    // a transaction can't go in the block
+    Rejected(BlockError),
+}
+
+impl fmt::Display for MiningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MiningError::Cancelled => write!(f, "mining was cancelled"),
+            MiningError::Rejected(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Shared flag used to stop mining, e.g. when a competing block changes the tip.
+#[derive(Clone)]
+pub struct CancellationToken {
+    cancelled: Vec<Arc<AtomicBool>>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            cancelled: vec![Arc::new(AtomicBool::new(false))],
+        }
+    }
+    pub fn cancel(&self) {
+        self.cancelled[0].store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+            .iter()
+            .any(|flag| flag.load(Ordering::SeqCst))
+    }
+    // a token that reports cancellation as soon as either token is cancelled
+    pub fn or(&self, other: &CancellationToken) -> CancellationToken {
+        let mut cancelled = self.cancelled.clone();
+        cancelled.extend(other.cancelled.iter().cloned());
+        CancellationToken { cancelled }
+    }
+}
+
+pub struct MiningResult {
+    pub nonce: Nonce,
+    pub hash: Hash,
+    pub timestamp: i64,
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningResult {
+    pub fn hashrate(&self) -> f64 {
+        hashrate(self.hashes, self.elapsed)
+    }
+}
+
+fn hashrate(hashes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return hashes as f64;
+    }
+    hashes as f64 / secs
 }
 
 impl<'a> ProofOfWork<'a> {
-    const TARGET_BITS: i32 = 8;
+    pub const TARGET_BITS: i32 = 8;
     const MAX_NONCE: i64 = i64::MAX;
+    // workers check the cancellation token once per batch of nonces
+    const BATCH_SIZE: i64 = 1024;
+    const HASHRATE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+    const TIMESTAMP_POLL_INTERVAL: Duration = Duration::from_millis(1);
 
     pub fn new(block: &'a Block) -> Self {
-        let target = BigInt::from(1) << (256 - Self::TARGET_BITS);
-        Self { block, target }
+        Self {
+            block,
+            target: Self::target(),
+        }
     }
 
-    fn prepare_data(&self, nonce: Nonce, hash_fn: HashFn) -> Vec<u8> {
+    pub fn target() -> BigInt {
+        BigInt::from(1) << (256 - Self::TARGET_BITS)
+    }
+
+    fn prepare_data(&self, tx_hash: &[u8], timestamp: i64, nonce: Nonce) -> Vec<u8> {
         [
             self.block.get_pre_block_hash().as_bytes(),
-            &self.block.hash_transactions(hash_fn),
-            &self.block.get_timestamp().to_be_bytes(),
+            tx_hash,
+            &timestamp.to_be_bytes(),
             &Self::TARGET_BITS.to_be_bytes(),
             &nonce.to_be_bytes(),
         ]
         .concat()
     }
 
+    // hash of the block header with the block's own timestamp and nonce
+    pub fn compute_hash(&self, hash_fn: HashFn) -> Vec<u8> {
+        let tx_hash = self.block.hash_transactions(hash_fn);
+        let data = self.prepare_data(
+            &tx_hash,
+            *self.block.get_timestamp(),
+            self.block.get_nonce(),
+        );
+        hash_fn(&data)
+    }
+
+    pub fn validate(&self, hash_fn: HashFn) -> bool {
+        let hash = self.compute_hash(hash_fn);
+        let hash_int = BigInt::from_bytes_be(Sign::Plus, &hash);
+        hash_int < self.target && HEXLOWER.encode(&hash).eq(self.block.get_hash())
+    }
+
     pub fn run(&self, hash_fn: HashFn) -> Result<(Nonce, Hash), MiningError> {
-        //todo: log instead of print
-        println!("Mining the block...");
-
-        (0..Self::MAX_NONCE)
-            .find_map(|nonce| {
-                let data = self.prepare_data(nonce, hash_fn);
-                let hash = hash_fn(&data);
-                let hash_int = BigInt::from_bytes_be(Sign::Plus, &hash);
-
-                if hash_int < self.target {
-                    let encoded_hash = HEXLOWER.encode(&hash);
-                    println!("Hash found: {}", encoded_hash);
-                    Some(Ok((nonce, encoded_hash)))
-                } else {
-                    None
+        let result = self.run_parallel(
+            hash_fn,
+            crate::utils::current_timestamp,
+            default_threads(),
+            &CancellationToken::new(),
+        )?;
+        Ok((result.nonce, result.hash))
+    }
+
+    // Splits the nonce space across `threads` workers. When a full round of
+    // nonces is exhausted the search restarts with a fresh timestamp, waiting
+    // for the clock to move on if needed.
+    pub fn run_parallel(
+        &self,
+        hash_fn: HashFn,
+        ts_provider: TimestampProvider,
+        threads: usize,
+        token: &CancellationToken,
+    ) -> Result<MiningResult, MiningError> {
+        info!("Mining the block with {} threads...", threads);
+        let threads = threads.max(1) as i64;
+        let tx_hash = self.block.hash_transactions(hash_fn);
+        let started = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let mut timestamp = *self.block.get_timestamp();
+
+        loop {
+            let found: Mutex<Option<(Nonce, Vec<u8>)>> = Mutex::new(None);
+            let stop = AtomicBool::new(false);
+            thread::scope(|scope| {
+                for worker in 0..threads {
+                    let (tx_hash, found, stop, hashes) = (&tx_hash, &found, &stop, &hashes);
+                    scope.spawn(move || {
+                        let mut nonce = worker;
+                        let mut last_report = Instant::now();
+                        while nonce < Self::MAX_NONCE {
+                            if stop.load(Ordering::Relaxed) || token.is_cancelled() {
+                                return;
+                            }
+                            let batch_start = nonce;
+                            let batch_end = nonce.saturating_add(Self::BATCH_SIZE * threads);
+                            while nonce < batch_end {
+                                let hash = hash_fn(&self.prepare_data(tx_hash, timestamp, nonce));
+                                if BigInt::from_bytes_be(Sign::Plus, &hash) < self.target {
+                                    let done = (nonce - batch_start) / threads + 1;
+                                    hashes.fetch_add(done as u64, Ordering::Relaxed);
+                                    stop.store(true, Ordering::SeqCst);
+                                    found.lock().unwrap().get_or_insert((nonce, hash));
+                                    return;
+                                }
+                                nonce = match nonce.checked_add(threads) {
+                                    Some(next) => next,
+                                    None => return,
+                                };
+                            }
+                            let total = hashes
+                                .fetch_add(Self::BATCH_SIZE as u64, Ordering::Relaxed)
+                                + Self::BATCH_SIZE as u64;
+                            if worker == 0
+                                && last_report.elapsed() >= Self::HASHRATE_REPORT_INTERVAL
+                            {
+                                info!("Hashrate: {:.0} H/s", hashrate(total, started.elapsed()));
+                                last_report = Instant::now();
+                            }
+                        }
+                    });
                 }
-            })
-            .unwrap_or_else(|| {
-                println!("Max nonce reached without finding a valid hash.");
-                Err(MiningError::NonceExhausted)
-            })
+            });
+
+            if let Some((nonce, hash)) = found.into_inner().unwrap() {
+                let encoded_hash = HEXLOWER.encode(&hash);
+                let result = MiningResult {
+                    nonce,
+                    hash: encoded_hash,
+                    timestamp,
+                    hashes: hashes.load(Ordering::Relaxed),
+                    elapsed: started.elapsed(),
+                };
+                info!("Hash found: {} ({:.0} H/s)", result.hash, result.hashrate());
+                return Ok(result);
+            }
+            if token.is_cancelled() {
+                info!("Mining cancelled");
+                return Err(MiningError::Cancelled);
+            }
+            info!("Nonce space exhausted, refreshing timestamp");
+            let mut refreshed = ts_provider();
+            while refreshed == timestamp {
+                if token.is_cancelled() {
+                    info!("Mining cancelled");
+                    return Err(MiningError::Cancelled);
+                }
+                thread::sleep(Self::TIMESTAMP_POLL_INTERVAL);
+                refreshed = ts_provider();
+            }
+            timestamp = refreshed;
+        }
     }
 }
+
+pub fn default_threads() -> usize {
+    thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1)
+}
@@ -0,0 +1,216 @@
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+
+use crate::block::{HashFn, SignFn};
+use crate::coin_selection::CoinControl;
+use crate::multisig::MultisigPolicy;
+use crate::script::{Opcode, Script};
+use crate::transaction::{TXOutput, Transaction};
+use crate::utxo_set::UTXOSet;
+use crate::wallet::{convert_address, hash_pub_key, WalletError, Wallets};
+
+// An unsigned or partially signed transaction, together with the outputs its
+// inputs spend, so it can be signed on a machine without the chain database.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PartiallySignedTransaction {
+    tx: Transaction,
+    prev_outputs: Vec<TXOutput>,
+    // per input, the redeem script of a pay-to-script-hash output if known
+    redeem_scripts: Vec<Option<Script>>,
+}
+
+impl PartiallySignedTransaction {
+    // Funds a payment from `from` without its private key, e.g. on a node that
+    // only watches the address. Change goes to `change_address`, or back to
+    // `from` when none is given.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        from: &str,
+        recipients: &[(String, i32)],
+        change_address: Option<&str>,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+    ) -> Result<PartiallySignedTransaction, WalletError> {
+        let mut wallets = Wallets::new();
+        let (tx, prev_outputs) = Transaction::fund(
+            from,
+            recipients,
+            Some(change_address.unwrap_or(from)),
+            fee,
+            coin_control,
+            utxo_set,
+            &mut wallets,
+            decoder,
+            hash_fn,
+        )?;
+        let redeem_scripts = prev_outputs
+            .iter()
+            .map(|out| Self::find_redeem_script(out, &wallets))
+            .collect();
+        Ok(PartiallySignedTransaction {
+            tx,
+            prev_outputs,
+            redeem_scripts,
+        })
+    }
+
+    fn find_redeem_script(prev_output: &TXOutput, wallets: &Wallets) -> Option<Script> {
+        let script_hash = prev_output.get_script_pubkey().as_p2sh()?;
+        wallets.get_redeem_script(script_hash)
+    }
+
+    // the multisig policy guarding input `idx`, bare or behind a script hash
+    pub fn get_input_policy(&self, idx: usize) -> Option<MultisigPolicy> {
+        match &self.redeem_scripts[idx] {
+            Some(redeem_script) => redeem_script.as_multisig(),
+            None => self.prev_outputs[idx].get_multisig(),
+        }
+    }
+
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn get_prev_outputs(&self) -> &[TXOutput] {
+        self.prev_outputs.as_slice()
+    }
+
+    // Sets the lock time and a relative lock on every input. Signatures made
+    // so far no longer match and are dropped.
+    pub fn set_locks(&mut self, lock_time: u32, sequence: u32, hash_fn: HashFn) {
+        self.tx.set_locks(lock_time, sequence, hash_fn);
+    }
+
+    pub fn get_fee(&self) -> i32 {
+        let input_value: i64 = self
+            .prev_outputs
+            .iter()
+            .filter(|out| out.is_native())
+            .map(|out| out.get_value() as i64)
+            .sum();
+        (input_value - self.tx.output_value()) as i32
+    }
+
+    // Signs every input, or multisig key slot, whose key is in `wallets` and
+    // returns how many signatures were added. The rest is left for other
+    // signers.
+    pub fn sign(
+        &mut self,
+        wallets: &Wallets,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<usize, WalletError> {
+        let mut signed = 0;
+        for idx in 0..self.prev_outputs.len() {
+            // a co-signer may know the redeem script the creator did not
+            if self.redeem_scripts[idx].is_none() {
+                self.redeem_scripts[idx] =
+                    Self::find_redeem_script(&self.prev_outputs[idx], wallets);
+            }
+            let prev_output = &self.prev_outputs[idx];
+            if let Some(policy) = self.get_input_policy(idx) {
+                let script_sig = self.tx.get_vin()[idx].get_script_sig().clone();
+                for (key_index, key) in policy.get_pub_keys().iter().enumerate() {
+                    let slot = script_sig.get_ops().get(key_index);
+                    if matches!(slot, Some(Opcode::Push(sig)) if !sig.is_empty()) {
+                        continue;
+                    }
+                    let address = convert_address(hash_pub_key(key.as_slice()).as_slice());
+                    let wallet = match wallets.get_signing_wallet(address.as_str()) {
+                        Ok(wallet) => wallet,
+                        Err(WalletError::Locked) => return Err(WalletError::Locked),
+                        Err(_) => continue,
+                    };
+                    self.tx.sign_multisig_input(
+                        idx,
+                        prev_output,
+                        self.redeem_scripts[idx].as_ref(),
+                        key_index,
+                        wallet.get_pkcs8(),
+                        hash_fn,
+                        sign_fn,
+                    );
+                    signed += 1;
+                }
+                continue;
+            }
+            let pub_key_hash = match prev_output.get_script_pubkey().as_p2pkh() {
+                Some(pub_key_hash) => pub_key_hash,
+                None => continue,
+            };
+            let address = convert_address(pub_key_hash);
+            let wallet = match wallets.get_signing_wallet(address.as_str()) {
+                Ok(wallet) => wallet,
+                Err(WalletError::Locked) => return Err(WalletError::Locked),
+                Err(_) => continue,
+            };
+            self.tx.sign_input(
+                idx,
+                prev_output,
+                wallet.get_pkcs8(),
+                wallet.get_public_key(),
+                hash_fn,
+                sign_fn,
+            );
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    // inputs still lacking a signature, or enough signatures for multisig
+    pub fn count_unsigned(&self) -> usize {
+        self.tx
+            .get_vin()
+            .iter()
+            .enumerate()
+            .filter(|(idx, vin)| match self.get_input_policy(*idx) {
+                Some(policy) => {
+                    vin.get_script_sig()
+                        .count_signatures(policy.get_pub_keys().len())
+                        < policy.get_required()
+                }
+                None => vin.get_script_sig().is_empty(),
+            })
+            .count()
+    }
+
+    // Adds the signatures of a copy signed by another co-signer.
+    pub fn combine(&mut self, other: &PartiallySignedTransaction) -> Result<(), WalletError> {
+        self.tx.merge_signatures(&other.tx)?;
+        for (redeem_script, other_script) in self
+            .redeem_scripts
+            .iter_mut()
+            .zip(other.redeem_scripts.iter())
+        {
+            if redeem_script.is_none() {
+                *redeem_script = other_script.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // the final transaction, once every input carries a signature
+    pub fn finalize(mut self, hash_fn: HashFn) -> Result<Transaction, WalletError> {
+        let unsigned = self.count_unsigned();
+        if unsigned > 0 {
+            return Err(WalletError::UnsignedInputs(unsigned));
+        }
+        self.tx.refresh_id(hash_fn);
+        Ok(self.tx)
+    }
+
+    pub fn to_hex(&self) -> String {
+        HEXLOWER.encode(bincode::serialize(self).unwrap().as_slice())
+    }
+
+    pub fn from_hex(data: &str) -> Result<PartiallySignedTransaction, WalletError> {
+        let bytes = HEXLOWER
+            .decode(data.trim().as_bytes())
+            .map_err(|e| WalletError::InvalidTransaction(e.to_string()))?;
+        bincode::deserialize(bytes.as_slice())
+            .map_err(|e| WalletError::InvalidTransaction(e.to_string()))
+    }
+}
@@ -0,0 +1,1016 @@
+use std::fmt;
+
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+
+use crate::block::SignVerifyFn;
+use crate::htlc::{HtlcContract, SECRET_LEN};
+use crate::multisig::{MultisigPolicy, MAX_MULTISIG_KEYS};
+use crate::wallet::hash_pub_key;
+
+// serialized size of one script
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+// non-push opcodes per script, counted in unexecuted branches too
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+pub const MAX_STACK_SIZE: usize = 1000;
+// leaves room for a redeem script with MAX_MULTISIG_KEYS uncompressed keys
+pub const MAX_ELEMENT_SIZE: usize = 1300;
+// byte length of numbers used in arithmetic and key counts
+const MAX_NUM_SIZE: usize = 4;
+// payload of an OP_RETURN data output
+pub const MAX_DATA_CARRIER_SIZE: usize = 80;
+// miner-chosen data after the height in a coinbase, e.g. an extra nonce
+pub const MAX_COINBASE_EXTRA_SIZE: usize = 64;
+// lock times are 5 bytes so timestamps past 2038 fit
+const MAX_LOCK_TIME_SIZE: usize = 5;
+// lock times below this are block heights, the rest unix timestamps in seconds
+pub const LOCK_TIME_THRESHOLD: i64 = 500_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Opcode {
+    Push(Vec<u8>),
+    Dup,
+    Drop,
+    Swap,
+    Size,
+    Equal,
+    EqualVerify,
+    Verify,
+    // marks the output unspendable
+    Return,
+    Sha256,
+    // ripemd160(sha256(x)), as used for addresses
+    Hash160,
+    If,
+    NotIf,
+    Else,
+    EndIf,
+    CheckSig,
+    CheckSigVerify,
+    // m <key>... n CHECKMULTISIG, with one signature slot per key below them;
+    // empty slots are skipped
+    CheckMultiSig,
+    CheckMultiSigVerify,
+    // fails unless the transaction's lock time is at or past the one on the
+    // stack, and of the same kind
+    CheckLockTimeVerify,
+    // fails unless the input's relative lock is at least the blocks on the stack
+    CheckSequenceVerify,
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Opcode::Push(data) if data.is_empty() => "OP_0",
+            Opcode::Push(data) => return write!(f, "{}", HEXLOWER.encode(data.as_slice())),
+            Opcode::Dup => "OP_DUP",
+            Opcode::Drop => "OP_DROP",
+            Opcode::Swap => "OP_SWAP",
+            Opcode::Size => "OP_SIZE",
+            Opcode::Equal => "OP_EQUAL",
+            Opcode::EqualVerify => "OP_EQUALVERIFY",
+            Opcode::Verify => "OP_VERIFY",
+            Opcode::Return => "OP_RETURN",
+            Opcode::Sha256 => "OP_SHA256",
+            Opcode::Hash160 => "OP_HASH160",
+            Opcode::If => "OP_IF",
+            Opcode::NotIf => "OP_NOTIF",
+            Opcode::Else => "OP_ELSE",
+            Opcode::EndIf => "OP_ENDIF",
+            Opcode::CheckSig => "OP_CHECKSIG",
+            Opcode::CheckSigVerify => "OP_CHECKSIGVERIFY",
+            Opcode::CheckMultiSig => "OP_CHECKMULTISIG",
+            Opcode::CheckMultiSigVerify => "OP_CHECKMULTISIGVERIFY",
+            Opcode::CheckLockTimeVerify => "OP_CHECKLOCKTIMEVERIFY",
+            Opcode::CheckSequenceVerify => "OP_CHECKSEQUENCEVERIFY",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    ScriptSize,
+    ElementSize,
+    OpCount,
+    StackSize,
+    StackUnderflow,
+    UnbalancedConditional,
+    PushOnly,
+    InvalidNumber,
+    InvalidMultisig,
+    InvalidRedeemScript,
+    Verify(&'static str),
+    OpReturn,
+    NegativeLockTime,
+    UnsatisfiedLockTime,
+    EvalFalse,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::ScriptSize => write!(f, "script is larger than {}", MAX_SCRIPT_SIZE),
+            ScriptError::ElementSize => {
+                write!(f, "stack element is larger than {}", MAX_ELEMENT_SIZE)
+            }
+            ScriptError::OpCount => write!(f, "more than {} opcodes", MAX_OPS_PER_SCRIPT),
+            ScriptError::StackSize => write!(f, "stack exceeds {} elements", MAX_STACK_SIZE),
+            ScriptError::StackUnderflow => write!(f, "stack underflow"),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced conditional"),
+            ScriptError::PushOnly => write!(f, "unlocking script must only push data"),
+            ScriptError::InvalidNumber => write!(f, "invalid number"),
+            ScriptError::InvalidMultisig => write!(f, "invalid multisig key or signature count"),
+            ScriptError::InvalidRedeemScript => write!(f, "redeem script cannot be decoded"),
+            ScriptError::Verify(op) => write!(f, "{} failed", op),
+            ScriptError::OpReturn => write!(f, "OP_RETURN executed"),
+            ScriptError::NegativeLockTime => write!(f, "negative lock time"),
+            ScriptError::UnsatisfiedLockTime => write!(f, "lock time not reached"),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+// What the scripts of one input are checked against: the signature hash of
+// the input, the lock time of the transaction and the relative lock of the
+// input. The locks themselves are enforced against the chain when the
+// transaction enters the memory pool or a block.
+pub struct ScriptContext<'a> {
+    message: &'a [u8],
+    sign_verify_fn: SignVerifyFn,
+    lock_time: u32,
+    sequence: u32,
+}
+
+impl<'a> ScriptContext<'a> {
+    pub fn new(
+        message: &'a [u8],
+        sign_verify_fn: SignVerifyFn,
+        lock_time: u32,
+        sequence: u32,
+    ) -> ScriptContext<'a> {
+        ScriptContext {
+            message,
+            sign_verify_fn,
+            lock_time,
+            sequence,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Script {
+    ops: Vec<Opcode>,
+}
+
+impl Script {
+    pub fn new(ops: Vec<Opcode>) -> Script {
+        Script { ops }
+    }
+
+    // OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG
+    pub fn p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Dup,
+            Opcode::Hash160,
+            Opcode::Push(pub_key_hash.to_vec()),
+            Opcode::EqualVerify,
+            Opcode::CheckSig,
+        ])
+    }
+
+    // OP_HASH160 <script_hash> OP_EQUAL, spent by pushing the redeem script
+    // after whatever the redeem script needs
+    pub fn p2sh(script_hash: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Hash160,
+            Opcode::Push(script_hash.to_vec()),
+            Opcode::Equal,
+        ])
+    }
+
+    pub fn p2pkh_unlock(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Push(signature.to_vec()),
+            Opcode::Push(pub_key.to_vec()),
+        ])
+    }
+
+    pub fn multisig(policy: &MultisigPolicy) -> Script {
+        let mut ops = vec![Opcode::Push(encode_num(policy.get_required() as i64))];
+        for key in policy.get_pub_keys() {
+            ops.push(Opcode::Push(key.clone()));
+        }
+        ops.push(Opcode::Push(encode_num(policy.get_pub_keys().len() as i64)));
+        ops.push(Opcode::CheckMultiSig);
+        Script::new(ops)
+    }
+
+    // one slot per key, left empty for keys that did not sign, followed by
+    // the redeem script when spending pay-to-script-hash
+    pub fn multisig_unlock(signatures: &[Vec<u8>], redeem_script: Option<&Script>) -> Script {
+        let mut ops: Vec<Opcode> = signatures.iter().cloned().map(Opcode::Push).collect();
+        if let Some(redeem_script) = redeem_script {
+            ops.push(Opcode::Push(redeem_script.to_bytes()));
+        }
+        Script::new(ops)
+    }
+
+    // OP_IF OP_SIZE <32> OP_EQUALVERIFY OP_SHA256 <secret_hash> OP_EQUALVERIFY
+    //     OP_DUP OP_HASH160 <recipient>
+    // OP_ELSE <lock_time> OP_CHECKLOCKTIMEVERIFY OP_DROP
+    //     OP_DUP OP_HASH160 <refund>
+    // OP_ENDIF OP_EQUALVERIFY OP_CHECKSIG
+    // The size check keeps a secret valid on one chain valid on the other.
+    pub fn htlc(contract: &HtlcContract) -> Script {
+        Script::new(vec![
+            Opcode::If,
+            Opcode::Size,
+            Opcode::Push(encode_num(SECRET_LEN as i64)),
+            Opcode::EqualVerify,
+            Opcode::Sha256,
+            Opcode::Push(contract.get_secret_hash().to_vec()),
+            Opcode::EqualVerify,
+            Opcode::Dup,
+            Opcode::Hash160,
+            Opcode::Push(contract.get_recipient().to_vec()),
+            Opcode::Else,
+            Opcode::Push(encode_num(contract.get_lock_time() as i64)),
+            Opcode::CheckLockTimeVerify,
+            Opcode::Drop,
+            Opcode::Dup,
+            Opcode::Hash160,
+            Opcode::Push(contract.get_refund().to_vec()),
+            Opcode::EndIf,
+            Opcode::EqualVerify,
+            Opcode::CheckSig,
+        ])
+    }
+
+    // takes the secret branch of an htlc script
+    pub fn htlc_redeem(signature: &[u8], pub_key: &[u8], secret: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Push(signature.to_vec()),
+            Opcode::Push(pub_key.to_vec()),
+            Opcode::Push(secret.to_vec()),
+            Opcode::Push(encode_num(1)),
+        ])
+    }
+
+    // takes the lock time branch of an htlc script
+    pub fn htlc_refund(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Push(signature.to_vec()),
+            Opcode::Push(pub_key.to_vec()),
+            Opcode::Push(vec![]),
+        ])
+    }
+
+    // the unlocking script of a coinbase input: <height> <extra_data>
+    pub fn coinbase(height: usize, extra_data: &[u8]) -> Script {
+        Script::new(vec![
+            Opcode::Push(encode_num(height as i64)),
+            Opcode::Push(extra_data.to_vec()),
+        ])
+    }
+
+    // OP_RETURN <data>, which fails whenever it is run
+    pub fn data_carrier(data: &[u8]) -> Script {
+        Script::new(vec![Opcode::Return, Opcode::Push(data.to_vec())])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Script> {
+        bincode::deserialize(bytes).ok()
+    }
+
+    pub fn get_ops(&self) -> &[Opcode] {
+        self.ops.as_slice()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.ops.iter().all(|op| matches!(op, Opcode::Push(_)))
+    }
+
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        hash_pub_key(self.to_bytes().as_slice())
+    }
+
+    // the pub_key_hash when this is the standard pay-to-pubkey-hash script
+    pub fn as_p2pkh(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Opcode::Dup, Opcode::Hash160, Opcode::Push(pub_key_hash), Opcode::EqualVerify, Opcode::CheckSig] => {
+                Some(pub_key_hash.as_slice())
+            }
+            _ => None,
+        }
+    }
+
+    // the redeem script hash when this is a pay-to-script-hash script
+    pub fn as_p2sh(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Opcode::Hash160, Opcode::Push(script_hash), Opcode::Equal] => {
+                Some(script_hash.as_slice())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_multisig(&self) -> Option<MultisigPolicy> {
+        let (last, rest) = self.ops.split_last()?;
+        if *last != Opcode::CheckMultiSig || rest.len() < 3 {
+            return None;
+        }
+        let mut pushes = vec![];
+        for op in rest {
+            match op {
+                Opcode::Push(data) => pushes.push(data.clone()),
+                _ => return None,
+            }
+        }
+        let required = decode_num(pushes[0].as_slice(), MAX_NUM_SIZE).ok()?;
+        let num_keys = decode_num(pushes[pushes.len() - 1].as_slice(), MAX_NUM_SIZE).ok()?;
+        let pub_keys = pushes[1..pushes.len() - 1].to_vec();
+        if num_keys != pub_keys.len() as i64 || required < 0 {
+            return None;
+        }
+        MultisigPolicy::new(required as usize, pub_keys).ok()
+    }
+
+    pub fn as_data_carrier(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Opcode::Return, Opcode::Push(data)] => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+
+    // the height and extra data of a coinbase input's script
+    pub fn as_coinbase(&self) -> Option<(usize, &[u8])> {
+        match self.ops.as_slice() {
+            [Opcode::Push(height), Opcode::Push(extra_data)]
+                if extra_data.len() <= MAX_COINBASE_EXTRA_SIZE =>
+            {
+                let height = decode_num(height.as_slice(), MAX_NUM_SIZE).ok()?;
+                if height < 0 {
+                    return None;
+                }
+                Some((height as usize, extra_data.as_slice()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_unspendable(&self) -> bool {
+        self.ops.first() == Some(&Opcode::Return)
+    }
+
+    pub fn as_htlc(&self) -> Option<HtlcContract> {
+        match self.ops.as_slice() {
+            [Opcode::If, Opcode::Size, Opcode::Push(secret_len), Opcode::EqualVerify, Opcode::Sha256, Opcode::Push(secret_hash), Opcode::EqualVerify, Opcode::Dup, Opcode::Hash160, Opcode::Push(recipient), Opcode::Else, Opcode::Push(lock_time), Opcode::CheckLockTimeVerify, Opcode::Drop, Opcode::Dup, Opcode::Hash160, Opcode::Push(refund), Opcode::EndIf, Opcode::EqualVerify, Opcode::CheckSig] =>
+            {
+                if decode_num(secret_len.as_slice(), MAX_NUM_SIZE).ok()? != SECRET_LEN as i64 {
+                    return None;
+                }
+                let lock_time = decode_num(lock_time.as_slice(), MAX_LOCK_TIME_SIZE).ok()?;
+                HtlcContract::new(
+                    secret_hash.clone(),
+                    recipient.clone(),
+                    refund.clone(),
+                    u32::try_from(lock_time).ok()?,
+                )
+                .ok()
+            }
+            _ => None,
+        }
+    }
+
+    // The hash wallets and the UTXO set know outputs with this script by: the
+    // pub_key_hash of pay-to-pubkey-hash, the redeem script hash of
+    // pay-to-script-hash, otherwise the hash of the script itself. A bare
+    // script and its pay-to-script-hash form therefore share one hash.
+    pub fn lock_hash(&self) -> Vec<u8> {
+        if let Some(pub_key_hash) = self.as_p2pkh() {
+            return pub_key_hash.to_vec();
+        }
+        if let Some(script_hash) = self.as_p2sh() {
+            return script_hash.to_vec();
+        }
+        self.hash()
+    }
+
+    // non-empty pushes among the first `slots`, i.e. signatures of a
+    // multisig unlock
+    pub fn count_signatures(&self, slots: usize) -> usize {
+        self.ops
+            .iter()
+            .take(slots)
+            .filter(|op| matches!(op, Opcode::Push(data) if !data.is_empty()))
+            .count()
+    }
+
+    // the public key of a pay-to-pubkey-hash unlock
+    pub fn get_unlock_pub_key(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Opcode::Push(_), Opcode::Push(pub_key)] if is_pub_key(pub_key) => {
+                Some(pub_key.as_slice())
+            }
+            _ => None,
+        }
+    }
+
+    // Fills pushes that are still empty here from another copy of the same
+    // unlocking script, e.g. multisig slots signed by another co-signer.
+    pub fn merge(&mut self, other: &Script) {
+        if self.ops.is_empty() {
+            self.ops = other.ops.clone();
+            return;
+        }
+        if self.ops.len() != other.ops.len() {
+            return;
+        }
+        for (op, other_op) in self.ops.iter_mut().zip(other.ops.iter()) {
+            if let (Opcode::Push(data), Opcode::Push(other_data)) = (op, other_op) {
+                if data.is_empty() {
+                    *data = other_data.clone();
+                }
+            }
+        }
+    }
+
+    fn execute(&self, stack: &mut Vec<Vec<u8>>, ctx: &ScriptContext) -> Result<(), ScriptError> {
+        if self.size() > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::ScriptSize);
+        }
+        // one entry per open IF, whether its branch currently runs
+        let mut exec_stack: Vec<bool> = vec![];
+        let mut op_count = 0;
+        for op in &self.ops {
+            let executing = exec_stack.iter().all(|branch| *branch);
+            if let Opcode::Push(data) = op {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(ScriptError::ElementSize);
+                }
+                if executing {
+                    stack.push(data.clone());
+                }
+            } else {
+                op_count += 1;
+                if op_count > MAX_OPS_PER_SCRIPT {
+                    return Err(ScriptError::OpCount);
+                }
+            }
+            match op {
+                Opcode::Push(_) => {}
+                Opcode::If | Opcode::NotIf => {
+                    let mut branch = false;
+                    if executing {
+                        branch = cast_to_bool(pop(stack)?.as_slice());
+                        if *op == Opcode::NotIf {
+                            branch = !branch;
+                        }
+                    }
+                    exec_stack.push(branch);
+                }
+                Opcode::Else => {
+                    let branch = exec_stack
+                        .last_mut()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    *branch = !*branch;
+                }
+                Opcode::EndIf => {
+                    exec_stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                _ if !executing => {}
+                Opcode::Dup => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                    stack.push(top);
+                }
+                Opcode::Drop => {
+                    pop(stack)?;
+                }
+                Opcode::Swap => {
+                    let a = pop(stack)?;
+                    let b = pop(stack)?;
+                    stack.push(a);
+                    stack.push(b);
+                }
+                Opcode::Size => {
+                    let len = stack.last().ok_or(ScriptError::StackUnderflow)?.len();
+                    stack.push(encode_num(len as i64));
+                }
+                Opcode::Equal | Opcode::EqualVerify => {
+                    let a = pop(stack)?;
+                    let b = pop(stack)?;
+                    push_or_verify(stack, a == b, *op == Opcode::EqualVerify, "OP_EQUALVERIFY")?;
+                }
+                Opcode::Verify => {
+                    if !cast_to_bool(pop(stack)?.as_slice()) {
+                        return Err(ScriptError::Verify("OP_VERIFY"));
+                    }
+                }
+                Opcode::Return => return Err(ScriptError::OpReturn),
+                Opcode::Sha256 => {
+                    let data = pop(stack)?;
+                    stack.push(crate::utils::sha256_digest(data.as_slice()));
+                }
+                Opcode::Hash160 => {
+                    let data = pop(stack)?;
+                    stack.push(hash_pub_key(data.as_slice()));
+                }
+                Opcode::CheckSig | Opcode::CheckSigVerify => {
+                    let pub_key = pop(stack)?;
+                    let signature = pop(stack)?;
+                    let valid = !signature.is_empty()
+                        && (ctx.sign_verify_fn)(
+                            pub_key.as_slice(),
+                            signature.as_slice(),
+                            ctx.message,
+                        );
+                    push_or_verify(
+                        stack,
+                        valid,
+                        *op == Opcode::CheckSigVerify,
+                        "OP_CHECKSIGVERIFY",
+                    )?;
+                }
+                Opcode::CheckMultiSig | Opcode::CheckMultiSigVerify => {
+                    let valid = check_multisig(stack, ctx)?;
+                    push_or_verify(
+                        stack,
+                        valid,
+                        *op == Opcode::CheckMultiSigVerify,
+                        "OP_CHECKMULTISIGVERIFY",
+                    )?;
+                }
+                Opcode::CheckLockTimeVerify => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let lock_time = decode_num(top.as_slice(), MAX_LOCK_TIME_SIZE)?;
+                    if lock_time < 0 {
+                        return Err(ScriptError::NegativeLockTime);
+                    }
+                    let tx_lock_time = ctx.lock_time as i64;
+                    // a height can't be compared with a timestamp
+                    if (lock_time < LOCK_TIME_THRESHOLD) != (tx_lock_time < LOCK_TIME_THRESHOLD)
+                        || lock_time > tx_lock_time
+                    {
+                        return Err(ScriptError::UnsatisfiedLockTime);
+                    }
+                }
+                Opcode::CheckSequenceVerify => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let blocks = decode_num(top.as_slice(), MAX_LOCK_TIME_SIZE)?;
+                    if blocks < 0 {
+                        return Err(ScriptError::NegativeLockTime);
+                    }
+                    if blocks > ctx.sequence as i64 {
+                        return Err(ScriptError::UnsatisfiedLockTime);
+                    }
+                }
+            }
+            if stack.len() > MAX_STACK_SIZE {
+                return Err(ScriptError::StackSize);
+            }
+        }
+        if !exec_stack.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops: Vec<String> = self.ops.iter().map(|op| op.to_string()).collect();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+// Runs the unlocking script of an input, then the locking script of the
+// output it spends on the resulting stack. The spend is valid when neither
+// fails and the top of the stack is true.
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    ctx: &ScriptContext,
+) -> Result<(), ScriptError> {
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::PushOnly);
+    }
+    let mut stack = vec![];
+    script_sig.execute(&mut stack, ctx)?;
+    let mut redeem_stack = stack.clone();
+    script_pubkey.execute(&mut stack, ctx)?;
+    check_top(&stack)?;
+
+    // pay-to-script-hash: the pushed redeem script matched the hash, now it
+    // runs on the rest of the unlocking stack
+    if script_pubkey.as_p2sh().is_some() {
+        let redeem_bytes = pop(&mut redeem_stack)?;
+        let redeem_script =
+            Script::from_bytes(redeem_bytes.as_slice()).ok_or(ScriptError::InvalidRedeemScript)?;
+        redeem_script.execute(&mut redeem_stack, ctx)?;
+        check_top(&redeem_stack)?;
+    }
+    Ok(())
+}
+
+fn check_top(stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top.as_slice()) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+// uncompressed P-256 point
+pub fn is_pub_key(data: &[u8]) -> bool {
+    data.len() == 65 && data[0] == 0x04
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow)
+}
+
+fn push_or_verify(
+    stack: &mut Vec<Vec<u8>>,
+    value: bool,
+    verify: bool,
+    op_name: &'static str,
+) -> Result<(), ScriptError> {
+    if verify {
+        if !value {
+            return Err(ScriptError::Verify(op_name));
+        }
+    } else {
+        stack.push(encode_bool(value));
+    }
+    Ok(())
+}
+
+// Pops n, n keys, m and n signature slots. Every non-empty slot must hold a
+// valid signature of its key, and at least m slots must be filled.
+fn check_multisig(stack: &mut Vec<Vec<u8>>, ctx: &ScriptContext) -> Result<bool, ScriptError> {
+    let num_keys = decode_num(pop(stack)?.as_slice(), MAX_NUM_SIZE)?;
+    if num_keys < 1 || num_keys as usize > MAX_MULTISIG_KEYS {
+        return Err(ScriptError::InvalidMultisig);
+    }
+    let num_keys = num_keys as usize;
+    let mut pub_keys = vec![];
+    for _ in 0..num_keys {
+        pub_keys.push(pop(stack)?);
+    }
+    pub_keys.reverse();
+    let required = decode_num(pop(stack)?.as_slice(), MAX_NUM_SIZE)?;
+    if required < 1 || required as usize > num_keys {
+        return Err(ScriptError::InvalidMultisig);
+    }
+    let mut signatures = vec![];
+    for _ in 0..num_keys {
+        signatures.push(pop(stack)?);
+    }
+    signatures.reverse();
+
+    let mut valid = 0;
+    for (pub_key, signature) in pub_keys.iter().zip(signatures.iter()) {
+        if signature.is_empty() {
+            continue;
+        }
+        if !(ctx.sign_verify_fn)(pub_key.as_slice(), signature.as_slice(), ctx.message) {
+            return Ok(false);
+        }
+        valid += 1;
+    }
+    Ok(valid >= required)
+}
+
+fn cast_to_bool(data: &[u8]) -> bool {
+    for (idx, byte) in data.iter().enumerate() {
+        if *byte != 0 {
+            // negative zero is false
+            return !(idx == data.len() - 1 && *byte == 0x80);
+        }
+    }
+    false
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+// little endian sign-magnitude with the sign in the top bit of the last byte
+pub fn encode_num(value: i64) -> Vec<u8> {
+    if value == 0 {
+        return vec![];
+    }
+    let negative = value < 0;
+    let mut abs = value.unsigned_abs();
+    let mut result = vec![];
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if result.last().unwrap() & 0x80 != 0 {
+        result.push(if negative { 0x80 } else { 0 });
+    } else if negative {
+        *result.last_mut().unwrap() |= 0x80;
+    }
+    result
+}
+
+pub fn decode_num(data: &[u8], max_size: usize) -> Result<i64, ScriptError> {
+    if data.len() > max_size {
+        return Err(ScriptError::InvalidNumber);
+    }
+    if data.is_empty() {
+        return Ok(0);
+    }
+    let mut result: i64 = 0;
+    for (idx, byte) in data.iter().enumerate() {
+        result |= (*byte as i64) << (8 * idx);
+    }
+    let sign_bit = 0x80_i64 << (8 * (data.len() - 1));
+    if result & sign_bit != 0 {
+        return Ok(-(result & !sign_bit));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::htlc::{hash_secret, new_secret};
+    use crate::utils::{ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify};
+    use crate::wallet::Wallet;
+
+    const MESSAGE: &[u8] = b"signature hash";
+
+    fn context(lock_time: u32) -> ScriptContext<'static> {
+        ScriptContext::new(MESSAGE, ecdsa_p256_sha256_sign_verify, lock_time, 0)
+    }
+
+    fn sign(wallet: &Wallet) -> Vec<u8> {
+        ecdsa_p256_sha256_sign_digest(wallet.get_pkcs8(), MESSAGE)
+    }
+
+    // runs `script_pubkey` alone, for errors raised by the interpreter itself
+    fn run(script_pubkey: Script) -> Result<(), ScriptError> {
+        verify_script(&Script::default(), &script_pubkey, &context(0))
+    }
+
+    #[test]
+    fn p2pkh_needs_the_key_and_its_signature() {
+        let wallet = Wallet::new();
+        let other = Wallet::new();
+        let script_pubkey = Script::p2pkh(&hash_pub_key(wallet.get_public_key()));
+        assert_eq!(
+            script_pubkey.as_p2pkh(),
+            Some(hash_pub_key(wallet.get_public_key()).as_slice())
+        );
+
+        let script_sig = Script::p2pkh_unlock(&sign(&wallet), wallet.get_public_key());
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &context(0)),
+            Ok(())
+        );
+
+        let wrong_key = Script::p2pkh_unlock(&sign(&other), other.get_public_key());
+        assert_eq!(
+            verify_script(&wrong_key, &script_pubkey, &context(0)),
+            Err(ScriptError::Verify("OP_EQUALVERIFY"))
+        );
+        let wrong_signature = Script::p2pkh_unlock(&sign(&other), wallet.get_public_key());
+        assert_eq!(
+            verify_script(&wrong_signature, &script_pubkey, &context(0)),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn multisig_counts_filled_slots() {
+        let wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+        let pub_keys = wallets
+            .iter()
+            .map(|w| w.get_public_key().to_vec())
+            .collect();
+        let policy = MultisigPolicy::new(2, pub_keys).unwrap();
+        let script_pubkey = Script::multisig(&policy);
+        assert_eq!(script_pubkey.as_multisig(), Some(policy));
+
+        let two_of_three = [sign(&wallets[0]), vec![], sign(&wallets[2])];
+        let script_sig = Script::multisig_unlock(&two_of_three, None);
+        assert_eq!(script_sig.count_signatures(3), 2);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &context(0)),
+            Ok(())
+        );
+
+        let one_of_three = [sign(&wallets[0]), vec![], vec![]];
+        let script_sig = Script::multisig_unlock(&one_of_three, None);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &context(0)),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn p2sh_runs_the_matching_redeem_script() {
+        let wallets: Vec<Wallet> = (0..2).map(|_| Wallet::new()).collect();
+        let pub_keys = wallets
+            .iter()
+            .map(|w| w.get_public_key().to_vec())
+            .collect();
+        let redeem_script = Script::multisig(&MultisigPolicy::new(1, pub_keys).unwrap());
+        let script_pubkey = Script::p2sh(&redeem_script.hash());
+        assert_eq!(
+            script_pubkey.as_p2sh(),
+            Some(redeem_script.hash().as_slice())
+        );
+        assert_eq!(script_pubkey.lock_hash(), redeem_script.lock_hash());
+
+        let signatures = [vec![], sign(&wallets[1])];
+        let script_sig = Script::multisig_unlock(&signatures, Some(&redeem_script));
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &context(0)),
+            Ok(())
+        );
+
+        let unsigned = Script::multisig_unlock(&[vec![], vec![]], Some(&redeem_script));
+        assert_eq!(
+            verify_script(&unsigned, &script_pubkey, &context(0)),
+            Err(ScriptError::EvalFalse)
+        );
+        let other_script = Script::p2pkh(&hash_pub_key(wallets[0].get_public_key()));
+        let script_sig = Script::multisig_unlock(&signatures, Some(&other_script));
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &context(0)),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn htlc_redeems_with_the_secret_or_refunds_after_the_lock_time() {
+        let recipient = Wallet::new();
+        let refund = Wallet::new();
+        let secret = new_secret();
+        let contract = HtlcContract::new(
+            hash_secret(&secret),
+            hash_pub_key(recipient.get_public_key()),
+            hash_pub_key(refund.get_public_key()),
+            100,
+        )
+        .unwrap();
+        let script_pubkey = Script::htlc(&contract);
+        assert_eq!(script_pubkey.as_htlc(), Some(contract));
+
+        let redeem = Script::htlc_redeem(&sign(&recipient), recipient.get_public_key(), &secret);
+        assert_eq!(verify_script(&redeem, &script_pubkey, &context(0)), Ok(()));
+        let wrong_secret =
+            Script::htlc_redeem(&sign(&recipient), recipient.get_public_key(), &new_secret());
+        assert_eq!(
+            verify_script(&wrong_secret, &script_pubkey, &context(0)),
+            Err(ScriptError::Verify("OP_EQUALVERIFY"))
+        );
+
+        let refund_sig = Script::htlc_refund(&sign(&refund), refund.get_public_key());
+        assert_eq!(
+            verify_script(&refund_sig, &script_pubkey, &context(99)),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        assert_eq!(
+            verify_script(&refund_sig, &script_pubkey, &context(100)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn data_carrier_is_unspendable() {
+        let script_pubkey = Script::data_carrier(b"anchor");
+        assert_eq!(script_pubkey.as_data_carrier(), Some(&b"anchor"[..]));
+        assert!(script_pubkey.is_unspendable());
+        assert_eq!(run(script_pubkey), Err(ScriptError::OpReturn));
+    }
+
+    #[test]
+    fn op_count_includes_unexecuted_branches() {
+        let mut ops = vec![Opcode::Push(vec![]), Opcode::If];
+        ops.extend(vec![Opcode::Dup; MAX_OPS_PER_SCRIPT - 2]);
+        ops.push(Opcode::EndIf);
+        ops.push(Opcode::Push(encode_num(1)));
+        assert_eq!(run(Script::new(ops.clone())), Ok(()));
+
+        ops.insert(2, Opcode::Dup);
+        assert_eq!(run(Script::new(ops)), Err(ScriptError::OpCount));
+    }
+
+    #[test]
+    fn stack_size_spans_both_scripts() {
+        let script_sig = Script::new(vec![Opcode::Push(vec![1]); MAX_STACK_SIZE / 2]);
+        let script_pubkey = Script::new(vec![Opcode::Push(vec![1]); MAX_STACK_SIZE / 2]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &context(0)),
+            Ok(())
+        );
+
+        let script_pubkey = Script::new(vec![Opcode::Push(vec![1]); MAX_STACK_SIZE / 2 + 1]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &context(0)),
+            Err(ScriptError::StackSize)
+        );
+    }
+
+    #[test]
+    fn element_size_is_limited() {
+        let largest = Script::new(vec![Opcode::Push(vec![1; MAX_ELEMENT_SIZE])]);
+        assert_eq!(run(largest), Ok(()));
+        let too_large = Script::new(vec![Opcode::Push(vec![1; MAX_ELEMENT_SIZE + 1])]);
+        assert_eq!(run(too_large), Err(ScriptError::ElementSize));
+    }
+
+    #[test]
+    fn conditionals_must_balance() {
+        let true_value = Opcode::Push(encode_num(1));
+        let unbalanced = [
+            vec![true_value.clone(), Opcode::If, true_value.clone()],
+            vec![true_value.clone(), Opcode::Else],
+            vec![true_value.clone(), Opcode::EndIf],
+            vec![
+                true_value.clone(),
+                Opcode::If,
+                true_value.clone(),
+                Opcode::EndIf,
+                Opcode::EndIf,
+            ],
+        ];
+        for ops in unbalanced {
+            assert_eq!(
+                run(Script::new(ops)),
+                Err(ScriptError::UnbalancedConditional)
+            );
+        }
+
+        let balanced = vec![
+            Opcode::Push(vec![]),
+            Opcode::If,
+            Opcode::Push(vec![]),
+            Opcode::Else,
+            true_value,
+            Opcode::EndIf,
+        ];
+        assert_eq!(run(Script::new(balanced)), Ok(()));
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        let values = [
+            0,
+            1,
+            -1,
+            127,
+            -127,
+            128,
+            -128,
+            255,
+            256,
+            -32768,
+            i32::MAX as i64,
+            -(i32::MAX as i64),
+        ];
+        for value in values {
+            let encoded = encode_num(value);
+            assert!(encoded.len() <= MAX_NUM_SIZE);
+            assert_eq!(decode_num(encoded.as_slice(), MAX_NUM_SIZE), Ok(value));
+        }
+        assert_eq!(encode_num(0), Vec::<u8>::new());
+        assert_eq!(encode_num(128), vec![0x80, 0]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+
+        // lock times need a fifth byte past i32::MAX
+        let lock_time = u32::MAX as i64;
+        let encoded = encode_num(lock_time);
+        assert_eq!(encoded.len(), MAX_LOCK_TIME_SIZE);
+        assert_eq!(
+            decode_num(encoded.as_slice(), MAX_NUM_SIZE),
+            Err(ScriptError::InvalidNumber)
+        );
+        assert_eq!(
+            decode_num(encoded.as_slice(), MAX_LOCK_TIME_SIZE),
+            Ok(lock_time)
+        );
+    }
+}
@@ -6,12 +6,18 @@ use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
 use crate::block::Block;
+use crate::block_template::{BlockTemplateBuilder, IssuedTemplate, IssuedTemplates};
 use crate::blockchain::Blockchain;
 use crate::config::GLOBAL_CONFIG;
+use crate::fee_estimator::FeeEstimator;
 use crate::memory_pool::{BlockInTransit, MemoryPool};
+use crate::miner::Miner;
 use crate::node::Nodes;
+use crate::proof_of_work::ProofOfWork;
+use crate::script::MAX_COINBASE_EXTRA_SIZE;
 use crate::transaction::Transaction;
 use crate::utxo_set::UTXOSet;
+use crate::wallet::validate_address;
 use data_encoding::HEXLOWER;
 // use log::{error, info};
 //use once_cell::sync::Lazy;
@@ -23,10 +29,10 @@ use std::{
     net::{Shutdown, SocketAddr, TcpStream},
     time::Duration,
 };
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
+pub(crate) static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
 
 static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
-pub const TRANSACTION_THRESHOLD: usize = 2;
+static GLOBAL_ISSUED_TEMPLATES: Lazy<IssuedTemplates> = Lazy::new(IssuedTemplates::new);
 const TCP_WRITE_TIMEOUT: u64 = 1000;
 const NODE_VERSION: usize = 1;
 pub const CENTERAL_NODE: &str = "127.0.0.1:2001";
@@ -48,19 +54,31 @@ impl Server {
     pub fn run(&self, addr: &str) {
         pub const CENTERAL_NODE: &str = "127.0.0.1:2001";
         let listener = TcpListener::bind(addr).unwrap();
+        // transactions and blocks are checked against the UTXO set
+        UTXOSet::new(self.blockchain.clone()).reindex();
 
         if addr.eq(CENTERAL_NODE) == false {
             let best_height = self.blockchain.get_best_height();
             send_version(CENTERAL_NODE, best_height);
         }
+        let miner = if GLOBAL_CONFIG.is_miner() {
+            let miner = Miner::new(self.blockchain.clone());
+            miner.start();
+            Some(miner)
+        } else {
+            None
+        };
         for stream in listener.incoming() {
             let blockchain = self.blockchain.clone();
-            thread::spawn(|| match stream {
+            let miner = miner.clone();
+            thread::spawn(move || match stream {
                 Ok(stream) => {
-                    todo!()
+                    if let Err(e) = serve(blockchain, miner.as_ref(), stream) {
+                        error!("Error on serving client: {}", e);
+                    }
                 }
                 Err(e) => {
-                    todo!()
+                    error!("Connection failed: {}", e);
                 }
             });
         }
@@ -99,6 +117,37 @@ pub enum Package {
         version: usize,
         best_height: usize,
     },
+    GetBlockTemplate {
+        addr_from: String,
+        mining_address: String,
+        // put in the coinbase, so miners sharing an address get distinct work
+        #[serde(default)]
+        extra_nonce: Vec<u8>,
+    },
+    BlockTemplate {
+        template_id: String,
+        pre_block_hash: String,
+        target: String,
+        target_bits: i32,
+        height: usize,
+        timestamp: i64,
+        coinbase_value: i32,
+        transactions: Vec<Vec<u8>>,
+    },
+    SubmitBlock {
+        addr_from: String,
+        template_id: String,
+        timestamp: i64,
+        nonce: i64,
+    },
+    SubmitBlockResult {
+        accepted: bool,
+        block_hash: String,
+        reason: String,
+    },
+    Error {
+        reason: String,
+    },
 }
 fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
     let socket_addr = addr.parse().unwrap();
@@ -182,7 +231,149 @@ fn send_data(addr: SocketAddr, pkg: Package) {
     let _ = stream.flush();
 }
 
-fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+// sends a request and waits for the node to answer on the same connection
+pub fn send_request(addr: &str, pkg: &Package) -> Result<Package, Box<dyn Error>> {
+    let stream = TcpStream::connect(addr)?;
+    let _ = stream.set_write_timeout(Option::from(Duration::from_millis(TCP_WRITE_TIMEOUT)));
+    serde_json::to_writer(&stream, pkg)?;
+    (&stream).flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut responses = Deserializer::from_reader(BufReader::new(&stream)).into_iter::<Package>();
+    match responses.next() {
+        Some(response) => Ok(response?),
+        None => Err("connection closed without a response".into()),
+    }
+}
+
+fn reply(stream: &TcpStream, pkg: &Package) {
+    info!("reply package: {:?}", pkg);
+    let mut writer = stream;
+    let _ = serde_json::to_writer(writer, pkg);
+    let _ = writer.flush();
+}
+
+fn get_block_template(
+    blockchain: &Blockchain,
+    mining_address: &str,
+    extra_nonce: &[u8],
+) -> Package {
+    if !validate_address(mining_address) {
+        return Package::Error {
+            reason: format!("invalid mining address {}", mining_address),
+        };
+    }
+    if extra_nonce.len() > MAX_COINBASE_EXTRA_SIZE {
+        return Package::Error {
+            reason: format!(
+                "extra nonce is longer than {} bytes",
+                MAX_COINBASE_EXTRA_SIZE
+            ),
+        };
+    }
+    let template = BlockTemplateBuilder::default().build(&GLOBAL_MEMORY_POOL.get_entries());
+    let pre_block_hash = String::from_utf8(blockchain.get_tip_hash_bytes()).unwrap();
+    let height = blockchain.get_best_height() + 1;
+    let coinbase_tx = Transaction::new_coinbase_tx(
+        crate::utils::sha256_digest,
+        crate::utils::base58_decode,
+        mining_address,
+        template.get_total_fees() as i32,
+        height,
+        extra_nonce,
+    )
+    .unwrap();
+    let mut txs = template.get_transactions().to_vec();
+    txs.push(coinbase_tx.clone());
+
+    let issued = IssuedTemplate::new(pre_block_hash.clone(), height, txs.clone());
+    let template_id = issued.id(crate::utils::sha256_digest);
+    GLOBAL_ISSUED_TEMPLATES.add(template_id.clone(), issued);
+
+    Package::BlockTemplate {
+        template_id,
+        pre_block_hash,
+        target: format!("{:064x}", ProofOfWork::target()),
+        target_bits: ProofOfWork::TARGET_BITS,
+        height,
+        timestamp: crate::utils::current_timestamp(),
+        coinbase_value: coinbase_tx.output_value() as i32,
+        transactions: txs.iter().map(|tx| tx.serialize().unwrap()).collect(),
+    }
+}
+
+fn submit_block(blockchain: &Blockchain, template_id: &str, timestamp: i64, nonce: i64) -> Package {
+    let issued = match GLOBAL_ISSUED_TEMPLATES.get(template_id) {
+        Some(issued) => issued,
+        None => {
+            return Package::SubmitBlockResult {
+                accepted: false,
+                block_hash: String::new(),
+                reason: format!("unknown or stale template {}", template_id),
+            }
+        }
+    };
+    let block = Block::assemble(
+        crate::utils::sha256_digest,
+        issued.get_pre_block_hash().clone(),
+        issued.get_transactions(),
+        issued.get_height(),
+        timestamp,
+        nonce,
+    );
+    match blockchain.accept_block(
+        &block,
+        crate::utils::sha256_digest,
+        crate::utils::ecdsa_p256_sha256_sign_verify,
+    ) {
+        Ok(()) => {
+            info!("Accepted submitted block {}", block.get_hash());
+            connect_block(blockchain, &block);
+            Package::SubmitBlockResult {
+                accepted: true,
+                block_hash: block.get_hash().clone(),
+                reason: String::new(),
+            }
+        }
+        Err(e) => Package::SubmitBlockResult {
+            accepted: false,
+            block_hash: block.get_hash().clone(),
+            reason: e.to_string(),
+        },
+    }
+}
+
+// updates the local indexes for a newly connected block and relays it
+pub(crate) fn connect_block(blockchain: &Blockchain, block: &Block) {
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    utxo_set.reindex();
+    FeeEstimator::new(blockchain.clone()).process_block(block);
+    for tx in block.get_transactions() {
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
+    }
+    broadcast_block(block);
+}
+
+pub(crate) fn broadcast_block(block: &Block) {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+    let nodes = GLOBAL_NODES.get_nodes();
+    for node in &nodes {
+        if node_addr.eq(node.get_addr().as_str()) {
+            continue;
+        }
+        send_inv(
+            node.get_addr().as_str(),
+            OpType::Block,
+            &[block.get_hash_bytes()],
+        );
+    }
+}
+
+fn serve(
+    blockchain: Blockchain,
+    miner: Option<&Miner>,
+    stream: TcpStream,
+) -> Result<(), Box<dyn Error>> {
     let peer_addr = stream.peer_addr()?;
     let reader = BufReader::new(&stream);
     let pkg_reader = Deserializer::from_reader(reader).into_iter::<Package>();
@@ -192,17 +383,38 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
         match pkg {
             Package::Block { addr_from, block } => {
                 let block = Block::deserialize(block.as_slice())?;
-                blockchain.add_block(&block);
-                info!("Added block {}", block.get_hash());
+                if blockchain
+                    .get_block(block.get_hash_bytes().as_slice())
+                    .is_none()
+                {
+                    match blockchain.accept_block(
+                        &block,
+                        crate::utils::sha256_digest,
+                        crate::utils::ecdsa_p256_sha256_sign_verify,
+                    ) {
+                        Ok(()) => {
+                            info!("Added block {}", block.get_hash());
+                            // the next block in transit is checked against it
+                            UTXOSet::new(blockchain.clone()).update(&block);
+                            FeeEstimator::new(blockchain.clone()).process_block(&block);
+                            for tx in block.get_transactions() {
+                                let txid_hex = HEXLOWER.encode(tx.get_id());
+                                GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
+                            }
+                        }
+                        Err(e) => {
+                            // the blocks still in transit build on this one
+                            info!("Rejected block {}: {}", block.get_hash(), e);
+                            GLOBAL_BLOCKS_IN_TRANSIT.clear();
+                        }
+                    }
+                }
 
                 if GLOBAL_BLOCKS_IN_TRANSIT.len() > 0 {
                     let block_hash = GLOBAL_BLOCKS_IN_TRANSIT.first().unwrap();
                     send_get_data(addr_from.as_str(), OpType::Block, &block_hash);
 
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash.as_slice());
-                } else {
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
                 }
             }
             Package::GetBlocks { addr_from } => {
@@ -232,12 +444,19 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 items,
             } => match op_type {
                 OpType::Block => {
-                    GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(items.as_slice());
-
-                    let block_hash = items.get(0).unwrap();
-                    send_get_data(addr_from.as_str(), OpType::Block, block_hash);
-
-                    GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
+                    // items come newest first, but a block is only accepted
+                    // on top of its parent
+                    let unknown: Vec<Vec<u8>> = items
+                        .iter()
+                        .rev()
+                        .filter(|block_hash| blockchain.get_block(block_hash).is_none())
+                        .cloned()
+                        .collect();
+                    if let Some(block_hash) = unknown.first() {
+                        GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(unknown.as_slice());
+                        send_get_data(addr_from.as_str(), OpType::Block, block_hash);
+                        GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
+                    }
                 }
                 OpType::Tx => {
                     let txid = items.get(0).unwrap();
@@ -254,7 +473,39 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
             } => {
                 let tx = Transaction::deserialize(transaction.as_slice());
                 let txid = tx.get_id_bytes();
-                GLOBAL_MEMORY_POOL.add(tx);
+                if !tx.is_standard() {
+                    info!(
+                        "Rejected non-standard transaction {}",
+                        HEXLOWER.encode(&txid)
+                    );
+                    continue;
+                }
+                // only transactions that could go in the next block are kept
+                // and relayed, e.g. not timelocked ones
+                let fee = match blockchain.check_transaction(
+                    &tx,
+                    GLOBAL_MEMORY_POOL.get_all().as_slice(),
+                    crate::utils::sha256_digest,
+                    crate::utils::ecdsa_p256_sha256_sign_verify,
+                ) {
+                    Ok(fee) => fee,
+                    Err(e) => {
+                        info!("Rejected transaction: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = GLOBAL_MEMORY_POOL.add(tx, fee) {
+                    info!("Rejected transaction: {}", e);
+                    continue;
+                }
+                if let Some(entry) = GLOBAL_MEMORY_POOL.get_entry(HEXLOWER.encode(&txid).as_str()) {
+                    FeeEstimator::new(blockchain.clone()).process_transaction(
+                        txid.as_slice(),
+                        entry.get_fee(),
+                        entry.get_size(),
+                        entry.get_time(),
+                    );
+                }
 
                 let node_addr = GLOBAL_CONFIG.get_node_addr();
 
@@ -271,45 +522,41 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                     }
                 }
 
-                if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
-                    let mining_address = GLOBAL_CONFIG.get_mining_addr().unwrap();
-                    let coinbase_tx = Transaction::new_coinbase_tx(
-                        crate::utils::sha256_digest,
-                        crate::utils::base58_decode,
-                        mining_address.as_str(),
-                    )
-                    .unwrap();
-                    let mut txs = GLOBAL_MEMORY_POOL.get_all();
-                    txs.push(coinbase_tx);
-
-                    let new_block = blockchain.mine_block(
-                        &txs,
-                        crate::utils::sha256_digest,
-                        crate::utils::ecdsa_p256_sha256_sign_verify,
-                        crate::utils::current_timestamp,
-                    );
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
-                    info!("New block {} is mined!", new_block.get_hash());
-
-                    for tx in &txs {
-                        let txid_hex = HEXLOWER.encode(tx.get_id());
-                        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
-                    }
-
-                    let nodes = GLOBAL_NODES.get_nodes();
-                    for node in &nodes {
-                        if node_addr.eq(node.get_addr().as_str()) {
-                            continue;
-                        }
-                        send_inv(
-                            node.get_addr().as_str(),
-                            OpType::Block,
-                            &vec![new_block.get_hash_bytes()],
-                        );
-                    }
+                if let Some(miner) = miner {
+                    miner.restart();
                 }
             }
+            Package::GetBlockTemplate {
+                addr_from: _,
+                mining_address,
+                extra_nonce,
+            } => {
+                let response = get_block_template(
+                    &blockchain,
+                    mining_address.as_str(),
+                    extra_nonce.as_slice(),
+                );
+                reply(&stream, &response);
+            }
+            Package::SubmitBlock {
+                addr_from: _,
+                template_id,
+                timestamp,
+                nonce,
+            } => {
+                let response = submit_block(&blockchain, template_id.as_str(), timestamp, nonce);
+                reply(&stream, &response);
+            }
+            Package::BlockTemplate { .. }
+            | Package::SubmitBlockResult { .. }
+            | Package::Error { .. } => {
+                reply(
+                    &stream,
+                    &Package::Error {
+                        reason: String::from("unexpected response package"),
+                    },
+                );
+            }
             Package::Version {
                 addr_from,
                 version,
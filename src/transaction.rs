@@ -1,38 +1,63 @@
 use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
+    asset::{self, AssetIssuance},
     block::{HashFn, SignFn, SignVerifyFn},
     blockchain::Blockchain,
-    utxo_set::UTXOSet,
-    wallet::{self, hash_pub_key, Wallets},
+    coin_selection::{CoinControl, SelectionParams},
+    config::GLOBAL_CONFIG,
+    fee_estimator::{self, FeeEstimator},
+    htlc::HtlcContract,
+    multisig::MultisigPolicy,
+    nft::{NonFungibleToken, METADATA_HASH_LEN},
+    script::{self, Opcode, Script, ScriptContext, LOCK_TIME_THRESHOLD, MAX_DATA_CARRIER_SIZE},
+    utxo_set::{UTXOSet, UnspentOutput},
+    wallet::{self, Wallet, WalletError, Wallets},
 };
 
+pub const SUBSIDY: i32 = 10;
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Transaction {
     id: Vec<u8>,
     vin: Vec<TXInput>,
     vout: Vec<TXOutput>,
+    // the first block height, or unix time of the tip, at which the
+    // transaction may be mined; heights are below LOCK_TIME_THRESHOLD
+    // and 0 means no lock
+    lock_time: u32,
+    // set when the transaction creates a new asset
+    issuance: Option<AssetIssuance>,
 }
 impl Transaction {
     pub fn get_id(&self) -> &[u8] {
         self.id.as_slice()
     }
 
+    // The coinbase of the block at `height` pays the block subsidy plus the
+    // fees of the block's transactions. The height makes its txid unique,
+    // `extra_data` is free for the miner, e.g. as an extra nonce.
     pub fn new_coinbase_tx(
         hash_fn: HashFn,
         decoder: fn(&str) -> Vec<u8>,
         to: &str,
+        fees: i32,
+        height: usize,
+        extra_data: &[u8],
     ) -> Result<Transaction, bincode::Error> {
-        const SUBSIDY: i32 = 10;
-        let txout = TXOutput::new(SUBSIDY, to, decoder);
-        let mut tx_input = TXInput::default();
-        tx_input.signature = Uuid::new_v4().as_bytes().to_vec();
+        let txout = TXOutput::new(SUBSIDY + fees, to, decoder);
+        let tx_input = TXInput {
+            script_sig: Script::coinbase(height, extra_data),
+            ..Default::default()
+        };
         let mut tx = Transaction {
             id: vec![],
             vin: vec![tx_input],
             vout: vec![txout],
+            lock_time: 0,
+            issuance: None,
         };
         tx.id = tx.hash(hash_fn)?;
         Ok(tx)
@@ -49,6 +74,8 @@ impl Transaction {
             id: vec![],
             vin: self.vin.clone(),
             vout: self.vout.clone(),
+            lock_time: self.lock_time,
+            issuance: self.issuance.clone(),
         };
         Ok(hash_fn(tx_copy.serialize()?.as_slice()))
         //crate::sha256_digest(tx_copy.serialize().unwrap().as_slice())
@@ -61,38 +88,242 @@ impl Transaction {
         blockchain: &Blockchain,
         hash_fn: HashFn,
         sign_verify_fn: SignVerifyFn,
+    ) -> bool {
+        self.verify_with_pending(blockchain, &[], hash_fn, sign_verify_fn)
+    }
+    // like verify, but previous transactions may also come from `pending`,
+    // e.g. an unconfirmed parent mined earlier in the same block
+    pub fn verify_with_pending(
+        &self,
+        blockchain: &Blockchain,
+        pending: &[Transaction],
+        hash_fn: HashFn,
+        sign_verify_fn: SignVerifyFn,
     ) -> bool {
         if self.is_coinbase() {
             return true;
         }
-        let mut tx_copy = self.trimmed_copy();
         for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
+            let prev_tx_option = find_prev_transaction(blockchain, pending, vin.get_txid());
             if prev_tx_option.is_none() {
                 panic!("ERROR: Previous transaction is not correct")
             }
             let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash(hash_fn).unwrap();
-            tx_copy.vin[idx].pub_key = vec![];
-
-            let verify = sign_verify_fn(
-                vin.pub_key.as_slice(),
-                vin.signature.as_slice(),
-                tx_copy.get_id(),
+            let prev_output = &prev_tx.vout[vin.vout];
+            let message = self.signature_hash(idx, &prev_output.script_pubkey, hash_fn);
+
+            let ctx = ScriptContext::new(
+                message.as_slice(),
+                sign_verify_fn,
+                self.lock_time,
+                vin.sequence,
             );
-            if !verify {
+            if script::verify_script(&vin.script_sig, &prev_output.script_pubkey, &ctx).is_err() {
                 return false;
             }
         }
         true
     }
+    // whether the lock time and the relative locks of the inputs let the
+    // transaction into the block after the current tip; outputs of `pending`
+    // transactions are unconfirmed and can't satisfy a relative lock
+    pub fn locks_satisfied(&self, blockchain: &Blockchain, pending: &[Transaction]) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+        let height = blockchain.get_best_height() + 1;
+        let time = blockchain
+            .get_block(blockchain.get_tip_hash_bytes().as_slice())
+            .map(|tip| *tip.get_timestamp() / 1000)
+            .unwrap_or(0);
+        if !self.is_final(height, time) {
+            return false;
+        }
+        self.vin.iter().all(|vin| {
+            if vin.sequence == 0 {
+                return true;
+            }
+            if pending.iter().any(|tx| tx.get_id() == vin.get_txid()) {
+                return false;
+            }
+            match blockchain.find_transaction_with_height(vin.get_txid()) {
+                Some((_, confirmed)) => height >= confirmed + vin.sequence as usize,
+                None => false,
+            }
+        })
+    }
+    // Inputs spending a coinbase output need it to be
+    // GLOBAL_CONFIG.get_coinbase_maturity() blocks deep in the next block, so a
+    // reorg can't take their funds away.
+    pub fn coinbase_spends_mature(&self, blockchain: &Blockchain, pending: &[Transaction]) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+        let height = blockchain.get_best_height() + 1;
+        let maturity = GLOBAL_CONFIG.get_coinbase_maturity();
+        self.vin.iter().all(|vin| {
+            if let Some(prev_tx) = pending.iter().find(|tx| tx.get_id() == vin.get_txid()) {
+                return !prev_tx.is_coinbase() || maturity == 0;
+            }
+            match blockchain.find_transaction_with_height(vin.get_txid()) {
+                Some((prev_tx, confirmed)) => {
+                    !prev_tx.is_coinbase() || height >= confirmed + maturity
+                }
+                // unknown inputs fail verification
+                None => true,
+            }
+        })
+    }
+    // whether the lock time has passed for a block at `height` after a tip
+    // with the unix `time`
+    pub fn is_final(&self, height: usize, time: i64) -> bool {
+        let lock_time = self.lock_time as i64;
+        if lock_time == 0 {
+            return true;
+        }
+        if lock_time < LOCK_TIME_THRESHOLD {
+            height as i64 >= lock_time
+        } else {
+            time >= lock_time
+        }
+    }
+    // Per asset, the outputs must carry exactly what the inputs bring in,
+    // plus the supply of an asset the transaction issues. A coinbase can't
+    // carry assets.
+    pub fn assets_balanced(
+        &self,
+        blockchain: &Blockchain,
+        pending: &[Transaction],
+        hash_fn: HashFn,
+    ) -> bool {
+        let mut balances: HashMap<Vec<u8>, i64> = HashMap::new();
+        if !self.is_coinbase() {
+            for vin in &self.vin {
+                let prev_output = find_prev_transaction(blockchain, pending, vin.get_txid())
+                    .and_then(|prev_tx| prev_tx.vout.get(vin.vout).cloned());
+                match prev_output {
+                    Some(prev_output) => {
+                        if let Some(asset) = prev_output.asset {
+                            *balances.entry(asset).or_default() += prev_output.value as i64;
+                        }
+                    }
+                    None => return false,
+                }
+            }
+        }
+        if let Some(issuance) = &self.issuance {
+            if self.is_coinbase() || !issuance.is_valid() {
+                return false;
+            }
+            let asset = asset::asset_id(self.vin[0].get_txid(), self.vin[0].get_vout(), hash_fn);
+            *balances.entry(asset).or_default() += issuance.get_supply() as i64;
+        }
+        for out in &self.vout {
+            if let Some(asset) = &out.asset {
+                if out.value <= 0 {
+                    return false;
+                }
+                *balances.entry(asset.clone()).or_default() -= out.value as i64;
+            }
+        }
+        balances.values().all(|balance| *balance == 0)
+    }
+    // Every token spent must reappear in exactly one output, or in a burn
+    // output. Any other token output must be minted here, from the first
+    // input's outpoint. A coinbase can't mint.
+    pub fn tokens_conserved(
+        &self,
+        blockchain: &Blockchain,
+        pending: &[Transaction],
+        hash_fn: HashFn,
+    ) -> bool {
+        let mut held = vec![];
+        if !self.is_coinbase() {
+            for vin in &self.vin {
+                let prev_output = find_prev_transaction(blockchain, pending, vin.get_txid())
+                    .and_then(|prev_tx| prev_tx.vout.get(vin.vout).cloned());
+                match prev_output {
+                    Some(prev_output) => held.extend(prev_output.nft),
+                    None => return false,
+                }
+            }
+        }
+        let mut seen = HashSet::new();
+        for out in &self.vout {
+            let token = match &out.nft {
+                Some(token) => token,
+                None => continue,
+            };
+            if out.value != 0 || out.asset.is_some() || !seen.insert(token.get_token_id()) {
+                return false;
+            }
+            if let Some(idx) = held.iter().position(|held| held == token) {
+                held.swap_remove(idx);
+                continue;
+            }
+            if self.is_coinbase() || token.get_metadata_hash().len() != METADATA_HASH_LEN {
+                return false;
+            }
+            let minted = NonFungibleToken::mint(
+                self.vin[0].get_txid(),
+                self.vin[0].get_vout(),
+                token.get_metadata_hash(),
+                hash_fn,
+            );
+            if &minted != token {
+                return false;
+            }
+        }
+        held.is_empty()
+    }
+    // sum of the native coins spent by this transaction, 0 for a coinbase;
+    // totals are i64 so no number of i32 values can overflow them
+    pub fn input_value(&self, blockchain: &Blockchain, pending: &[Transaction]) -> Option<i64> {
+        if self.is_coinbase() {
+            return Some(0);
+        }
+        let mut value = 0;
+        for vin in &self.vin {
+            let prev_tx = find_prev_transaction(blockchain, pending, vin.get_txid())?;
+            let prev_output = prev_tx.vout.get(vin.vout)?;
+            if prev_output.is_native() {
+                value += prev_output.value as i64;
+            }
+        }
+        Some(value)
+    }
+    // native outputs pay a positive amount, only data outputs may pay nothing
+    pub fn output_values_valid(&self) -> bool {
+        self.vout
+            .iter()
+            .filter(|out| out.is_native())
+            .all(|out| out.value > 0 || (out.value == 0 && out.script_pubkey.is_unspendable()))
+    }
+    // relay policy: unspendable outputs only carry up to
+    // MAX_DATA_CARRIER_SIZE bytes of data
+    pub fn is_standard(&self) -> bool {
+        self.vout
+            .iter()
+            .filter(|out| out.script_pubkey.is_unspendable())
+            .all(|out| match out.script_pubkey.as_data_carrier() {
+                Some(data) => data.len() <= MAX_DATA_CARRIER_SIZE,
+                None => false,
+            })
+    }
+    // sum of the native coins paid out, assets left out
+    pub fn output_value(&self) -> i64 {
+        self.vout
+            .iter()
+            .filter(|out| out.is_native())
+            .map(|out| out.value as i64)
+            .sum()
+    }
     fn trimmed_copy(&self) -> Transaction {
         let mut inputs = vec![];
         let mut outputs = vec![];
         for input in &self.vin {
-            let txinput = TXInput::new(input.get_txid(), input.get_vout());
+            let mut txinput = TXInput::new(input.get_txid(), input.get_vout());
+            txinput.sequence = input.sequence;
             inputs.push(txinput);
         }
         for output in &self.vout {
@@ -102,10 +333,24 @@ impl Transaction {
             id: self.id.clone(),
             vin: inputs,
             vout: outputs,
+            lock_time: self.lock_time,
+            issuance: self.issuance.clone(),
         }
     }
     pub fn is_coinbase(&self) -> bool {
-        return self.vin.len() == 1 && self.vin[0].pub_key.len() == 0;
+        self.vin.len() == 1 && self.vin[0].txid.is_empty()
+    }
+
+    // the block height a coinbase commits to, None for other transactions
+    // or a malformed coinbase
+    pub fn get_coinbase_height(&self) -> Option<usize> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        self.vin[0]
+            .script_sig
+            .as_coinbase()
+            .map(|(height, _)| height)
     }
 
     pub fn get_vout(&self) -> &[TXOutput] {
@@ -115,92 +360,736 @@ impl Transaction {
     pub fn get_vin(&self) -> &[TXInput] {
         self.vin.as_slice()
     }
+
+    pub fn get_lock_time(&self) -> u32 {
+        self.lock_time
+    }
+
+    pub fn get_issuance(&self) -> Option<&AssetIssuance> {
+        self.issuance.as_ref()
+    }
+
+    // the id of the asset this transaction issues
+    pub fn get_issued_asset(&self, hash_fn: HashFn) -> Option<Vec<u8>> {
+        self.issuance.as_ref()?;
+        let first = self.vin.first()?;
+        Some(asset::asset_id(first.get_txid(), first.get_vout(), hash_fn))
+    }
     pub fn deserialize(bytes: &[u8]) -> Transaction {
         bincode::deserialize(bytes).unwrap()
     }
     pub fn get_id_bytes(&self) -> Vec<u8> {
         self.id.clone()
     }
+    // serialized size of a signed P2PKH transaction, used to price fees
+    // before the inputs are signed
+    pub fn estimate_size(num_inputs: usize, num_outputs: usize) -> usize {
+        const BASE_SIZE: usize = 8 + 32 + 8 + 8 + 4;
+        // txid, vout, an unlocking script pushing a signature and a key, and
+        // the sequence
+        const INPUT_SIZE: usize = 8 + 32 + 8 + 8 + 2 * (4 + 8) + 64 + 65 + 4;
+        // value and the five opcodes of the pay-to-pubkey-hash script
+        const OUTPUT_SIZE: usize = 4 + 8 + 5 * 4 + 8 + 20;
+        BASE_SIZE + num_inputs * INPUT_SIZE + num_outputs * OUTPUT_SIZE
+    }
+    // `fee` of None asks the fee estimator for the rate needed to confirm
+    // within fee_estimator::DEFAULT_CONFIRM_TARGET blocks
+    #[allow(clippy::too_many_arguments)]
     pub fn new_utxo_transaction(
         from: &str,
         to: &str,
         amount: i32,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<Transaction, WalletError> {
+        Self::new_utxo_transaction_many(
+            from,
+            &[(String::from(to), amount)],
+            fee,
+            coin_control,
+            utxo_set,
+            decoder,
+            hash_fn,
+            sign_fn,
+        )
+    }
+    // pays every (address, amount) pair from one set of inputs with at most
+    // one change output
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_utxo_transaction_many(
+        from: &str,
+        recipients: &[(String, i32)],
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<Transaction, WalletError> {
+        let mut wallets = Wallets::new();
+        let wallet = wallets.get_signing_wallet(from)?.clone();
+        let (mut tx, prev_outputs) = Self::fund(
+            from,
+            recipients,
+            None,
+            fee,
+            coin_control,
+            utxo_set,
+            &mut wallets,
+            decoder,
+            hash_fn,
+        )?;
+        tx.sign_inputs(prev_outputs.as_slice(), &wallet, hash_fn, sign_fn);
+        Ok(tx)
+    }
+    // embeds `data` in a zero-value data output, paying only the fee
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_data_transaction(
+        from: &str,
+        data: &[u8],
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<Transaction, WalletError> {
+        let mut wallets = Wallets::new();
+        let wallet = wallets.get_signing_wallet(from)?.clone();
+        let (mut tx, prev_outputs) = Self::fund_outputs(
+            from,
+            vec![TXOutput::new_data(data)?],
+            None,
+            fee,
+            coin_control,
+            utxo_set,
+            &mut wallets,
+            decoder,
+            hash_fn,
+        )?;
+        tx.sign_inputs(prev_outputs.as_slice(), &wallet, hash_fn, sign_fn);
+        Ok(tx)
+    }
+    // issues a new asset with its whole supply paid to `to`; the fee is paid
+    // in native coins from `from`
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_issuance_transaction(
+        from: &str,
+        issuance: AssetIssuance,
+        to: &str,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<Transaction, WalletError> {
+        let mut wallets = Wallets::new();
+        let wallet = wallets.get_signing_wallet(from)?.clone();
+        // the asset id depends on the first input, known once coins are selected
+        let supply = new_asset_output(issuance.get_supply(), &[], to, decoder)?;
+        let (mut tx, prev_outputs) = Self::fund_outputs(
+            from,
+            vec![supply],
+            None,
+            fee,
+            coin_control,
+            utxo_set,
+            &mut wallets,
+            decoder,
+            hash_fn,
+        )?;
+        if tx.vin.is_empty() {
+            return Err(WalletError::InvalidAsset(String::from(
+                "an issuance must spend at least one coin, pay a fee",
+            )));
+        }
+        tx.issuance = Some(issuance);
+        let asset = tx.get_issued_asset(hash_fn).unwrap();
+        for out in tx.vout.iter_mut().filter(|out| out.asset.is_some()) {
+            out.asset = Some(asset.clone());
+        }
+        tx.id = tx.hash(hash_fn).unwrap();
+        tx.sign_inputs(prev_outputs.as_slice(), &wallet, hash_fn, sign_fn);
+        Ok(tx)
+    }
+    // mints a token for `metadata_hash` held by `to`; the fee is paid in
+    // native coins from `from`
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_mint_transaction(
+        from: &str,
+        metadata_hash: &[u8],
+        to: &str,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<Transaction, WalletError> {
+        if metadata_hash.len() != METADATA_HASH_LEN {
+            return Err(WalletError::InvalidToken(format!(
+                "the metadata hash must be {} bytes",
+                METADATA_HASH_LEN
+            )));
+        }
+        let mut wallets = Wallets::new();
+        let wallet = wallets.get_signing_wallet(from)?.clone();
+        // the token id depends on the first input, known once coins are selected
+        let placeholder = NonFungibleToken::mint(&[], 0, metadata_hash, hash_fn);
+        let (mut tx, prev_outputs) = Self::fund_outputs(
+            from,
+            vec![new_nft_output(placeholder, to, decoder)?],
+            None,
+            fee,
+            coin_control,
+            utxo_set,
+            &mut wallets,
+            decoder,
+            hash_fn,
+        )?;
+        if tx.vin.is_empty() {
+            return Err(WalletError::InvalidToken(String::from(
+                "a mint must spend at least one coin, pay a fee",
+            )));
+        }
+        let token = NonFungibleToken::mint(
+            tx.vin[0].get_txid(),
+            tx.vin[0].get_vout(),
+            metadata_hash,
+            hash_fn,
+        );
+        for out in tx.vout.iter_mut().filter(|out| out.nft.is_some()) {
+            out.nft = Some(token.clone());
+        }
+        tx.id = tx.hash(hash_fn).unwrap();
+        tx.sign_inputs(prev_outputs.as_slice(), &wallet, hash_fn, sign_fn);
+        Ok(tx)
+    }
+    // moves the token `token_id` held by `from` to `to`, or burns it when `to`
+    // is None; the fee is paid in native coins
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_nft_transaction(
+        from: &str,
+        token_id: &[u8],
+        to: Option<&str>,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<Transaction, WalletError> {
+        let mut wallets = Wallets::new();
+        let wallet = wallets.get_signing_wallet(from)?.clone();
+        let public_key_hash = destination_hash(from, decoder)?;
+        let held = utxo_set
+            .find_nft(token_id)
+            .filter(|utxo| {
+                utxo.get_output()
+                    .is_locked_with_key(public_key_hash.as_slice())
+            })
+            .ok_or_else(|| {
+                WalletError::InvalidToken(format!(
+                    "{} doesn't hold {}",
+                    from,
+                    HEXLOWER.encode(token_id)
+                ))
+            })?;
+        if wallets.is_frozen(&held.get_outpoint()) {
+            return Err(WalletError::InvalidToken(String::from(
+                "the output holding the token is frozen",
+            )));
+        }
+        let token = held.get_output().nft.clone().unwrap();
+        let output = match to {
+            Some(to) => new_nft_output(token, to, decoder)?,
+            None => TXOutput::new_burn(token),
+        };
+        let (mut tx, mut prev_outputs) = Self::fund_outputs(
+            from,
+            vec![output],
+            None,
+            fee,
+            coin_control,
+            utxo_set,
+            &mut wallets,
+            decoder,
+            hash_fn,
+        )?;
+        let outpoint = held.get_outpoint();
+        tx.vin
+            .push(TXInput::new(outpoint.get_txid(), outpoint.get_vout()));
+        prev_outputs.push(held.get_output().clone());
+        tx.id = tx.hash(hash_fn).unwrap();
+        tx.sign_inputs(prev_outputs.as_slice(), &wallet, hash_fn, sign_fn);
+        Ok(tx)
+    }
+    // pays `amount` of `asset` to `to`, with the change in the asset going to
+    // a new change address and the fee paid in native coins
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_asset_transaction(
+        from: &str,
+        to: &str,
+        asset: &[u8],
+        amount: i32,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
         utxo_set: &UTXOSet,
         decoder: fn(&str) -> Vec<u8>,
         hash_fn: HashFn,
         sign_fn: SignFn,
-    ) -> Transaction {
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).expect("unable to found wallet");
-        let public_key_hash = hash_pub_key(wallet.get_public_key());
+    ) -> Result<Transaction, WalletError> {
+        if amount <= 0 {
+            return Err(WalletError::InvalidRecipient(String::from(
+                "amount must be positive",
+            )));
+        }
+        let mut wallets = Wallets::new();
+        let wallet = wallets.get_signing_wallet(from)?.clone();
+        let public_key_hash = destination_hash(from, decoder)?;
+        let mut owned = utxo_set.find_asset_outputs(public_key_hash.as_slice(), asset);
+        owned.retain(|utxo| !wallets.is_frozen(&utxo.get_outpoint()));
+        owned.sort_by_key(|utxo| std::cmp::Reverse(utxo.get_value()));
+        let mut selected = vec![];
+        let mut total = 0;
+        for utxo in owned {
+            if total >= amount {
+                break;
+            }
+            total += utxo.get_value();
+            selected.push(utxo);
+        }
+        if total < amount {
+            return Err(WalletError::InsufficientFunds);
+        }
 
-        let (accumulated, valid_outputs) =
-            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount);
-        if accumulated < amount {
-            panic!("Error: Not enough funds")
+        let mut outputs = vec![new_asset_output(amount, asset, to, decoder)?];
+        if total > amount {
+            let change_address = wallets.new_change_address()?;
+            outputs.push(new_asset_output(
+                total - amount,
+                asset,
+                change_address.as_str(),
+                decoder,
+            )?);
+        }
+        let (mut tx, mut prev_outputs) = Self::fund_outputs(
+            from,
+            outputs,
+            None,
+            fee,
+            coin_control,
+            utxo_set,
+            &mut wallets,
+            decoder,
+            hash_fn,
+        )?;
+        for utxo in selected {
+            let outpoint = utxo.get_outpoint();
+            tx.vin
+                .push(TXInput::new(outpoint.get_txid(), outpoint.get_vout()));
+            prev_outputs.push(utxo.get_output().clone());
+        }
+        tx.id = tx.hash(hash_fn).unwrap();
+        tx.sign_inputs(prev_outputs.as_slice(), &wallet, hash_fn, sign_fn);
+        Ok(tx)
+    }
+    // Spends the htlc output `contract` to `to`, as its recipient revealing
+    // `secret`, or as its refunder when `secret` is None. A refund carries the
+    // contract's lock time and can't be mined before it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_htlc_spend(
+        contract: &UnspentOutput,
+        secret: Option<&[u8]>,
+        to: &str,
+        fee: Option<i32>,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) -> Result<Transaction, WalletError> {
+        let prev_output = contract.get_output();
+        if !prev_output.is_native() {
+            return Err(WalletError::InvalidContract(String::from(
+                "contracts holding assets can't be spent",
+            )));
+        }
+        let htlc = prev_output.script_pubkey.as_htlc().ok_or_else(|| {
+            WalletError::InvalidContract(format!("{} is not a contract", contract.get_outpoint()))
+        })?;
+        if let Some(secret) = secret {
+            if !htlc.matches_secret(secret) {
+                return Err(WalletError::InvalidContract(String::from(
+                    "the secret does not match the secret hash",
+                )));
+            }
         }
+        let signer = match secret {
+            Some(_) => wallet::convert_address(htlc.get_recipient()),
+            None => wallet::convert_address(htlc.get_refund()),
+        };
+        let wallet = wallets.get_signing_wallet(signer.as_str())?;
+        let fee = match fee {
+            Some(fee) => fee,
+            None => {
+                let fee_rate = FeeEstimator::new(utxo_set.get_blockchain().clone())
+                    .estimate_fee(fee_estimator::DEFAULT_CONFIRM_TARGET)
+                    .unwrap_or(fee_estimator::MIN_FEE_RATE);
+                fee_estimator::fee_for_size(fee_rate, Self::estimate_size(1, 1))
+            }
+        };
+        if prev_output.value - fee <= 0 {
+            return Err(WalletError::InsufficientFunds);
+        }
+        let outpoint = contract.get_outpoint();
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput::new(outpoint.get_txid(), outpoint.get_vout())],
+            vout: vec![new_output(prev_output.value - fee, to, decoder)?],
+            lock_time: match secret {
+                Some(_) => 0,
+                None => htlc.get_lock_time(),
+            },
+            issuance: None,
+        };
+        tx.id = tx.hash(hash_fn).unwrap();
 
-        let mut inputs = vec![];
-        for (txid_hex, outs) in valid_outputs {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            for out in outs {
-                let input = TXInput {
-                    txid: txid.clone(),
-                    vout: out,
-                    signature: vec![],
-                    pub_key: wallet.get_public_key().to_vec(),
-                };
-                inputs.push(input);
+        let message = tx.signature_hash(0, &prev_output.script_pubkey, hash_fn);
+        let signature = sign_fn(wallet.get_pkcs8(), message.as_slice());
+        tx.vin[0].script_sig = match secret {
+            Some(secret) => Script::htlc_redeem(&signature, wallet.get_public_key(), secret),
+            None => Script::htlc_refund(&signature, wallet.get_public_key()),
+        };
+        Ok(tx)
+    }
+    // Selects coins and builds the transaction without signing it. Returns
+    // the outputs spent by the inputs, in input order.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn fund(
+        from: &str,
+        recipients: &[(String, i32)],
+        change_address: Option<&str>,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        wallets: &mut Wallets,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+    ) -> Result<(Transaction, Vec<TXOutput>), WalletError> {
+        if recipients.is_empty() {
+            return Err(WalletError::InvalidRecipient(String::from(
+                "no recipients given",
+            )));
+        }
+        let mut seen = HashSet::new();
+        for (address, amount) in recipients {
+            if !wallet::validate_destination(address.as_str()) {
+                return Err(WalletError::InvalidRecipient(format!(
+                    "{} is not a valid address",
+                    address
+                )));
+            }
+            if *amount <= 0 {
+                return Err(WalletError::InvalidRecipient(format!(
+                    "amount for {} must be positive",
+                    address
+                )));
+            }
+            if !seen.insert(address.as_str()) {
+                return Err(WalletError::InvalidRecipient(format!(
+                    "{} is listed more than once",
+                    address
+                )));
             }
         }
+        let outputs = recipients
+            .iter()
+            .map(|(address, amount)| new_output(*amount, address.as_str(), decoder))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::fund_outputs(
+            from,
+            outputs,
+            change_address,
+            fee,
+            coin_control,
+            utxo_set,
+            wallets,
+            decoder,
+            hash_fn,
+        )
+    }
+    // like fund, for outputs that are already built
+    #[allow(clippy::too_many_arguments)]
+    fn fund_outputs(
+        from: &str,
+        mut outputs: Vec<TXOutput>,
+        change_address: Option<&str>,
+        fee: Option<i32>,
+        coin_control: &CoinControl,
+        utxo_set: &UTXOSet,
+        wallets: &mut Wallets,
+        decoder: fn(&str) -> Vec<u8>,
+        hash_fn: HashFn,
+    ) -> Result<(Transaction, Vec<TXOutput>), WalletError> {
+        // assets come from inputs the caller adds, coin selection only pays
+        // the native amount and the fee
+        let total = outputs
+            .iter()
+            .filter(|out| out.is_native())
+            .try_fold(0i32, |total, out| total.checked_add(out.value))
+            // coin selection adds a fixed fee to the amount
+            .filter(|total| total.checked_add(fee.unwrap_or(0)).is_some())
+            .ok_or_else(|| WalletError::InvalidRecipient(String::from("amounts overflow")))?;
 
-        let mut outputs = vec![TXOutput::new(amount, to, decoder)];
+        let public_key_hash = destination_hash(from, decoder)?;
+        let fee_rate = FeeEstimator::new(utxo_set.get_blockchain().clone())
+            .estimate_fee(fee_estimator::DEFAULT_CONFIRM_TARGET)
+            .unwrap_or(fee_estimator::MIN_FEE_RATE);
+        let params = SelectionParams::new(total, outputs.len(), fee_rate, fee);
+        let owned = utxo_set.find_spendable_outputs(public_key_hash.as_slice());
+        let selection =
+            coin_control.select(&owned, |outpoint| wallets.is_frozen(outpoint), &params)?;
 
-        if accumulated > amount {
-            outputs.push(TXOutput::new(accumulated - amount, from, decoder)) // to: 币收入
+        let mut inputs = vec![];
+        let mut prev_outputs = vec![];
+        for utxo in selection.get_inputs() {
+            let outpoint = utxo.get_outpoint();
+            inputs.push(TXInput::new(outpoint.get_txid(), outpoint.get_vout()));
+            prev_outputs.push(utxo.get_output().clone());
+        }
+
+        if selection.get_change() > 0 {
+            let change_address = match change_address {
+                Some(address) => String::from(address),
+                None => wallets.new_change_address()?,
+            };
+            let change = new_output(selection.get_change(), change_address.as_str(), decoder)?;
+            // a fixed position would give the change output away
+            let position = crate::utils::random_bytes(1)[0] as usize % (outputs.len() + 1);
+            outputs.insert(position, change);
         }
 
         let mut tx = Transaction {
             id: vec![],
             vin: inputs,
             vout: outputs,
+            lock_time: 0,
+            issuance: None,
         };
 
         tx.id = tx.hash(hash_fn).unwrap();
-
-        tx.sign(
-            utxo_set.get_blockchain(),
-            wallet.get_pkcs8(),
-            hash_fn,
-            sign_fn,
-        );
-        return tx;
+        Ok((tx, prev_outputs))
     }
-    fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8], hash_fn: HashFn, sign_fn: SignFn) {
+    // The message signed for input `idx`: the transaction without unlocking
+    // scripts, with the spent output's locking script in that input.
+    pub(crate) fn signature_hash(
+        &self,
+        idx: usize,
+        prev_script_pubkey: &Script,
+        hash_fn: HashFn,
+    ) -> Vec<u8> {
         let mut tx_copy = self.trimmed_copy();
-
-        for (idx, vin) in self.vin.iter_mut().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct")
+        tx_copy.vin[idx].script_sig = prev_script_pubkey.clone();
+        tx_copy.hash(hash_fn).unwrap()
+    }
+    // unlocks every input with `wallet`'s key, `prev_outputs` in input order
+    fn sign_inputs(
+        &mut self,
+        prev_outputs: &[TXOutput],
+        wallet: &Wallet,
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) {
+        for (idx, prev_output) in prev_outputs.iter().enumerate() {
+            self.sign_input(
+                idx,
+                prev_output,
+                wallet.get_pkcs8(),
+                wallet.get_public_key(),
+                hash_fn,
+                sign_fn,
+            );
+        }
+    }
+    // unlocks a pay-to-pubkey-hash output
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn sign_input(
+        &mut self,
+        idx: usize,
+        prev_output: &TXOutput,
+        pkcs8: &[u8],
+        pub_key: &[u8],
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) {
+        let message = self.signature_hash(idx, &prev_output.script_pubkey, hash_fn);
+        let signature = sign_fn(pkcs8, message.as_slice());
+        self.vin[idx].script_sig = Script::p2pkh_unlock(signature.as_slice(), pub_key);
+    }
+    // signs input `idx` for the key at `key_index` of the spent output's
+    // policy; `redeem_script` is the multisig script when `prev_output` is its
+    // pay-to-script-hash form
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn sign_multisig_input(
+        &mut self,
+        idx: usize,
+        prev_output: &TXOutput,
+        redeem_script: Option<&Script>,
+        key_index: usize,
+        pkcs8: &[u8],
+        hash_fn: HashFn,
+        sign_fn: SignFn,
+    ) {
+        let policy = match redeem_script {
+            Some(redeem_script) => redeem_script.as_multisig(),
+            None => prev_output.get_multisig(),
+        };
+        let num_keys = match policy {
+            Some(policy) => policy.get_pub_keys().len(),
+            None => return,
+        };
+        let message = self.signature_hash(idx, &prev_output.script_pubkey, hash_fn);
+        let mut signatures: Vec<Vec<u8>> = self.vin[idx]
+            .script_sig
+            .get_ops()
+            .iter()
+            .map(|op| match op {
+                Opcode::Push(data) => data.clone(),
+                _ => vec![],
+            })
+            .collect();
+        signatures.resize(num_keys, vec![]);
+        signatures[key_index] = sign_fn(pkcs8, message.as_slice());
+        self.vin[idx].script_sig = Script::multisig_unlock(signatures.as_slice(), redeem_script);
+    }
+    // Copies signatures from another copy of the same transaction into inputs
+    // and multisig slots that are still unsigned here.
+    pub(crate) fn merge_signatures(&mut self, other: &Transaction) -> Result<(), WalletError> {
+        if self.id != other.id {
+            return Err(WalletError::InvalidTransaction(String::from(
+                "the transactions to combine differ",
+            )));
+        }
+        for (vin, other_vin) in self.vin.iter_mut().zip(other.vin.iter()) {
+            vin.script_sig.merge(&other_vin.script_sig);
+        }
+        Ok(())
+    }
+    // Sets the lock time and the relative lock of every input. Both are
+    // signed, so this must happen before signing.
+    pub(crate) fn set_locks(&mut self, lock_time: u32, sequence: u32, hash_fn: HashFn) {
+        self.lock_time = lock_time;
+        for vin in self.vin.iter_mut() {
+            vin.sequence = sequence;
+            vin.script_sig = Script::default();
+        }
+        self.refresh_id(hash_fn);
+    }
+    // the id never covers unlocking scripts, so signing leaves it unchanged
+    pub(crate) fn refresh_id(&mut self, hash_fn: HashFn) {
+        self.id = self.compute_id(hash_fn);
+    }
+    // whether the declared id is the one the contents hash to
+    pub fn has_valid_id(&self, hash_fn: HashFn) -> bool {
+        self.id == self.compute_id(hash_fn)
+    }
+    // a coinbase's unlocking script is the exception: it carries the height
+    // that makes the id unique
+    fn compute_id(&self, hash_fn: HashFn) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        if !self.is_coinbase() {
+            for vin in unsigned.vin.iter_mut() {
+                vin.script_sig = Script::default();
             }
-            let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash(hash_fn).unwrap();
-            tx_copy.vin[idx].pub_key = vec![];
-
-            let signature = sign_fn(pkcs8, tx_copy.get_id());
-            vin.signature = signature;
         }
+        unsigned.hash(hash_fn).unwrap()
+    }
+}
+// the pub_key_hash outputs paying to `destination` are locked with, for a
+// base58 address, a multi(...) policy or an htlc(...) contract
+pub fn destination_hash(
+    destination: &str,
+    decoder: fn(&str) -> Vec<u8>,
+) -> Result<Vec<u8>, WalletError> {
+    if MultisigPolicy::is_descriptor(destination) {
+        return Ok(destination.parse::<MultisigPolicy>()?.hash());
+    }
+    if HtlcContract::is_descriptor(destination) {
+        return Ok(destination.parse::<HtlcContract>()?.hash());
+    }
+    if !wallet::validate_address(destination) {
+        return Err(WalletError::InvalidRecipient(format!(
+            "{} is not a valid address",
+            destination
+        )));
+    }
+    let payload = decoder(destination);
+    Ok(payload[1..payload.len() - wallet::ADDRESS_CHECK_SUM_LEN].to_vec())
+}
+
+fn new_output(
+    value: i32,
+    destination: &str,
+    decoder: fn(&str) -> Vec<u8>,
+) -> Result<TXOutput, WalletError> {
+    if MultisigPolicy::is_descriptor(destination) {
+        return Ok(TXOutput::new_multisig(value, destination.parse()?));
     }
+    if HtlcContract::is_descriptor(destination) {
+        let contract: HtlcContract = destination.parse()?;
+        return Ok(TXOutput::new_script(value, Script::htlc(&contract)));
+    }
+    Ok(TXOutput::new(value, destination, decoder))
+}
+
+fn new_asset_output(
+    value: i32,
+    asset: &[u8],
+    destination: &str,
+    decoder: fn(&str) -> Vec<u8>,
+) -> Result<TXOutput, WalletError> {
+    let mut output = new_output(value, destination, decoder)?;
+    output.asset = Some(asset.to_vec());
+    Ok(output)
+}
+
+fn new_nft_output(
+    token: NonFungibleToken,
+    destination: &str,
+    decoder: fn(&str) -> Vec<u8>,
+) -> Result<TXOutput, WalletError> {
+    let mut output = new_output(0, destination, decoder)?;
+    output.nft = Some(token);
+    Ok(output)
 }
+
+fn find_prev_transaction(
+    blockchain: &Blockchain,
+    pending: &[Transaction],
+    txid: &[u8],
+) -> Option<Transaction> {
+    pending
+        .iter()
+        .find(|tx| tx.get_id() == txid)
+        .cloned()
+        .or_else(|| blockchain.find_transaction(txid))
+}
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct TXInput {
     txid: Vec<u8>,
     vout: usize,
-    signature: Vec<u8>,
-    pub_key: Vec<u8>,
+    // satisfies the locking script of the spent output
+    script_sig: Script,
+    // relative lock: blocks the spent output must have been confirmed for,
+    // 0 for none
+    sequence: u32,
 }
 
 impl TXInput {
@@ -208,8 +1097,8 @@ impl TXInput {
         TXInput {
             txid: txid.to_vec(),
             vout,
-            signature: vec![],
-            pub_key: vec![],
+            script_sig: Script::default(),
+            sequence: 0,
         }
     }
     pub fn get_txid(&self) -> &[u8] {
@@ -218,43 +1107,140 @@ impl TXInput {
     pub fn get_vout(&self) -> usize {
         self.vout
     }
-    pub fn get_pub_key(&self) -> &[u8] {
-        self.pub_key.as_slice()
+    pub fn get_script_sig(&self) -> &Script {
+        &self.script_sig
     }
-    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
-        let locking_hash = wallet::hash_pub_key(self.pub_key.as_slice());
-        return locking_hash.eq(pub_key_hash);
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
     }
 }
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TXOutput {
+    // native coins, or units of `asset` when set
     value: i32,
-    pub_key_hash: Vec<u8>,
+    script_pubkey: Script,
+    asset: Option<Vec<u8>>,
+    // a non-fungible token, held by an output of value 0
+    nft: Option<NonFungibleToken>,
 }
 
 impl TXOutput {
     pub fn new(value: i32, address: &str, decoder: fn(&str) -> Vec<u8>) -> TXOutput {
         let mut output = TXOutput {
             value,
-            pub_key_hash: vec![],
+            script_pubkey: Script::default(),
+            asset: None,
+            nft: None,
         };
         output.lock(address, decoder);
         return output;
     }
+    pub fn new_multisig(value: i32, policy: MultisigPolicy) -> TXOutput {
+        TXOutput {
+            value,
+            script_pubkey: Script::multisig(&policy),
+            asset: None,
+            nft: None,
+        }
+    }
+    // a provably unspendable output carrying up to MAX_DATA_CARRIER_SIZE bytes
+    pub fn new_data(data: &[u8]) -> Result<TXOutput, WalletError> {
+        if data.is_empty() || data.len() > MAX_DATA_CARRIER_SIZE {
+            return Err(WalletError::InvalidRecipient(format!(
+                "data outputs carry 1 to {} bytes",
+                MAX_DATA_CARRIER_SIZE
+            )));
+        }
+        Ok(TXOutput {
+            value: 0,
+            script_pubkey: Script::data_carrier(data),
+            asset: None,
+            nft: None,
+        })
+    }
+    // destroys `token`: the output can't be spent, so it never reaches the
+    // UTXO set
+    pub fn new_burn(token: NonFungibleToken) -> TXOutput {
+        TXOutput {
+            value: 0,
+            script_pubkey: Script::data_carrier(token.get_token_id()),
+            asset: None,
+            nft: Some(token),
+        }
+    }
+    pub fn new_script(value: i32, script_pubkey: Script) -> TXOutput {
+        TXOutput {
+            value,
+            script_pubkey,
+            asset: None,
+            nft: None,
+        }
+    }
     fn lock(&mut self, address: &str, decoder: fn(&str) -> Vec<u8>) {
         let payload = decoder(address);
-        let pub_key_hash = payload[1..payload.len() - wallet::ADDRESS_CHECK_SUM_LEN].to_vec();
-        self.pub_key_hash = pub_key_hash;
+        let hash = &payload[1..payload.len() - wallet::ADDRESS_CHECK_SUM_LEN];
+        self.script_pubkey = if payload[0] == wallet::SCRIPT_VERSION {
+            Script::p2sh(hash)
+        } else {
+            Script::p2pkh(hash)
+        };
     }
     pub fn get_value(&self) -> i32 {
         self.value
     }
 
-    pub fn get_pub_key_hash(&self) -> &[u8] {
-        self.pub_key_hash.as_slice()
+    pub fn get_script_pubkey(&self) -> &Script {
+        &self.script_pubkey
+    }
+
+    pub fn get_asset(&self) -> Option<&[u8]> {
+        self.asset.as_deref()
+    }
+
+    pub fn get_nft(&self) -> Option<&NonFungibleToken> {
+        self.nft.as_ref()
+    }
+
+    pub fn is_native(&self) -> bool {
+        self.asset.is_none() && self.nft.is_none()
+    }
+
+    // the pub_key_hash of pay-to-pubkey-hash outputs, the script hash otherwise,
+    // see Script::lock_hash
+    pub fn get_pub_key_hash(&self) -> Vec<u8> {
+        self.script_pubkey.lock_hash()
+    }
+
+    pub fn get_multisig(&self) -> Option<MultisigPolicy> {
+        self.script_pubkey.as_multisig()
+    }
+
+    // the address, or multi(...) descriptor, the output pays to
+    pub fn get_destination(&self) -> String {
+        if let Some(pub_key_hash) = self.script_pubkey.as_p2pkh() {
+            return wallet::convert_address(pub_key_hash);
+        }
+        if let Some(script_hash) = self.script_pubkey.as_p2sh() {
+            return wallet::convert_script_address(script_hash);
+        }
+        if let Some(contract) = self.script_pubkey.as_htlc() {
+            return contract.to_string();
+        }
+        if let Some(data) = self.script_pubkey.as_data_carrier() {
+            return format!("data {}", HEXLOWER.encode(data));
+        }
+        match self.get_multisig() {
+            Some(policy) => policy.to_string(),
+            None => format!("script {}", self.script_pubkey),
+        }
+    }
+
+    // outputs no script can unlock are never added to the UTXO set
+    pub fn is_unspendable(&self) -> bool {
+        self.script_pubkey.is_unspendable()
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash.eq(pub_key_hash)
+        self.script_pubkey.lock_hash().eq(pub_key_hash)
     }
 }
@@ -2,8 +2,9 @@ use std::iter::repeat;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crypto::digest::Digest;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
 use ring::digest::{Context, SHA256};
-use ring::rand::SystemRandom;
+use ring::rand::{SecureRandom, SystemRandom};
 use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
 
 use crate::blockchain::Blockchain;
@@ -61,3 +62,40 @@ pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
     ripemd160.result(&mut buf);
     return buf;
 }
+// memory-hard key derivation for wallet passphrases
+pub fn argon2_derive_key(passphrase: &[u8], salt: &[u8], m_cost: u32, t_cost: u32) -> Vec<u8> {
+    let params = argon2::Params::new(m_cost, t_cost, 1, Some(32)).expect("invalid argon2 params");
+    let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+    let mut key = vec![0u8; 32];
+    argon2
+        .hash_password_into(passphrase, salt, &mut key)
+        .expect("unable to derive key");
+    key
+}
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("unable to generate random bytes");
+    bytes
+}
+// ChaCha20-Poly1305, returns nonce || ciphertext || tag
+pub fn aead_seal(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).unwrap());
+    let nonce_bytes = random_bytes(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&nonce_bytes).unwrap();
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(nonce, Aad::empty(), &mut in_out)
+        .expect("unable to encrypt");
+    [nonce_bytes, in_out].concat()
+}
+pub fn aead_open(key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).ok()?);
+    let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).ok()?;
+    let mut in_out = sealed[NONCE_LEN..].to_vec();
+    let plaintext = key.open_in_place(nonce, Aad::empty(), &mut in_out).ok()?;
+    Some(plaintext.to_vec())
+}
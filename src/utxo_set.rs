@@ -1,7 +1,15 @@
-use crate::{block::Block, blockchain::Blockchain, transaction::TXOutput};
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    config::GLOBAL_CONFIG,
+    transaction::{TXOutput, Transaction},
+};
 
-use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 
 const UTXO_TREE: &str = "chainstate";
 
@@ -18,54 +26,131 @@ impl UTXOSet {
         &self.blockchain
     }
 
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> (i32, HashMap<String, Vec<usize>>) {
-        let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut accmulated = 0;
+    fn unspent_outputs_of(value: &[u8]) -> Vec<UnspentOutput> {
+        bincode::deserialize(value)
+            .expect("unable to deserialize UTXO entry, run reindexutxo to rebuild it")
+    }
+
+    fn find_outputs(&self, pub_key_hash: &[u8], asset: Option<&[u8]>) -> Vec<UnspentOutput> {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let mut utxos = vec![];
         for item in utxo_tree.iter() {
-            let (k, v) = item.unwrap();
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TXOutput");
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accmulated < amount {
-                    accmulated += out.get_value();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(idx);
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
+            let (_, v) = item.unwrap();
+            for utxo in Self::unspent_outputs_of(v.as_ref()) {
+                if utxo.output.is_locked_with_key(pub_key_hash)
+                    && utxo.output.get_asset() == asset
+                    && utxo.output.get_nft().is_none()
+                {
+                    utxos.push(utxo)
                 }
             }
         }
-        (accmulated, unspent_outputs)
+        utxos
     }
 
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
+    // native coins only, see find_asset_outputs for assets
+    pub fn find_unspent_outputs(&self, pub_key_hash: &[u8]) -> Vec<UnspentOutput> {
+        self.find_outputs(pub_key_hash, None)
+    }
+
+    // native outputs coin selection may spend: coinbase outputs only once
+    // they're mature in the next block
+    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8]) -> Vec<UnspentOutput> {
+        let height = self.blockchain.get_best_height() + 1;
+        let maturity = GLOBAL_CONFIG.get_coinbase_maturity();
+        self.find_unspent_outputs(pub_key_hash)
+            .into_iter()
+            .filter(|utxo| utxo.is_mature(height, maturity))
+            .collect()
+    }
+
+    // coinbase rewards held by `pub_key_hash` that can't be spent yet
+    pub fn get_immature_balance(&self, pub_key_hash: &[u8]) -> i32 {
+        let height = self.blockchain.get_best_height() + 1;
+        let maturity = GLOBAL_CONFIG.get_coinbase_maturity();
+        self.find_unspent_outputs(pub_key_hash)
+            .iter()
+            .filter(|utxo| !utxo.is_mature(height, maturity))
+            .map(|utxo| utxo.get_value())
+            .sum()
+    }
+
+    pub fn find_asset_outputs(&self, pub_key_hash: &[u8], asset: &[u8]) -> Vec<UnspentOutput> {
+        self.find_outputs(pub_key_hash, Some(asset))
+    }
+
+    // the tokens held by `pub_key_hash`
+    pub fn find_nfts(&self, pub_key_hash: &[u8]) -> Vec<UnspentOutput> {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
         let mut utxos = vec![];
         for item in utxo_tree.iter() {
             let (_, v) = item.unwrap();
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())
-                .expect("unable to deserialize TXOutput");
-            for out in outs.iter() {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone())
+            for utxo in Self::unspent_outputs_of(v.as_ref()) {
+                if utxo.output.get_nft().is_some() && utxo.output.is_locked_with_key(pub_key_hash) {
+                    utxos.push(utxo)
                 }
             }
         }
         utxos
     }
 
+    // the output holding `token_id`, None once burned or never minted
+    pub fn find_nft(&self, token_id: &[u8]) -> Option<UnspentOutput> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        for item in utxo_tree.iter() {
+            let (_, v) = item.unwrap();
+            let found = Self::unspent_outputs_of(v.as_ref())
+                .into_iter()
+                .find(|utxo| {
+                    utxo.output
+                        .get_nft()
+                        .is_some_and(|token| token.get_token_id() == token_id)
+                });
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    // units of every asset held by `pub_key_hash`, by asset id
+    pub fn find_asset_balances(&self, pub_key_hash: &[u8]) -> BTreeMap<Vec<u8>, i32> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let mut balances = BTreeMap::new();
+        for item in utxo_tree.iter() {
+            let (_, v) = item.unwrap();
+            for utxo in Self::unspent_outputs_of(v.as_ref()) {
+                if !utxo.output.is_locked_with_key(pub_key_hash) {
+                    continue;
+                }
+                if let Some(asset) = utxo.output.get_asset() {
+                    *balances.entry(asset.to_vec()).or_insert(0) += utxo.get_value();
+                }
+            }
+        }
+        balances
+    }
+
+    pub fn get_unspent_output(&self, outpoint: &OutPoint) -> Option<UnspentOutput> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let v = utxo_tree.get(outpoint.get_txid()).unwrap()?;
+        Self::unspent_outputs_of(v.as_ref())
+            .into_iter()
+            .find(|utxo| utxo.vout == outpoint.get_vout())
+    }
+
+    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
+        self.find_unspent_outputs(pub_key_hash)
+            .into_iter()
+            .map(|utxo| utxo.output)
+            .collect()
+    }
+
     pub fn count_transactions(&self) -> i32 {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
@@ -95,27 +180,21 @@ impl UTXOSet {
         for tx in block.get_transactions() {
             if tx.is_coinbase() == false {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = vec![];
                     let outs_bytes = utxo_tree.get(vin.get_txid()).unwrap().unwrap();
-                    let outs: Vec<TXOutput> = bincode::deserialize(outs_bytes.as_ref())
-                        .expect("unable to deserialize TXOutput");
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone())
-                        }
-                    }
-                    if updated_outs.len() == 0 {
+                    let mut outs = Self::unspent_outputs_of(outs_bytes.as_ref());
+                    outs.retain(|utxo| utxo.vout != vin.get_vout());
+                    if outs.is_empty() {
                         let _ = utxo_tree.remove(vin.get_txid()).unwrap();
                     } else {
-                        let outs_bytes = bincode::serialize(&updated_outs)
-                            .expect("unable to serialize TXOutput");
+                        let outs_bytes =
+                            bincode::serialize(&outs).expect("unable to serialize TXOutput");
                         utxo_tree.insert(vin.get_txid(), outs_bytes).unwrap();
                     }
                 }
             }
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone())
+            let new_outputs = UnspentOutput::from_transaction(tx, block.get_height());
+            if new_outputs.is_empty() {
+                continue;
             }
             let outs_bytes =
                 bincode::serialize(&new_outputs).expect("unable to serialize TXOutput");
@@ -123,3 +202,97 @@ impl UTXOSet {
         }
     }
 }
+
+// Reference to one output of a transaction, written as txid_hex:vout
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    txid: Vec<u8>,
+    vout: usize,
+}
+
+impl OutPoint {
+    pub fn new(txid: &[u8], vout: usize) -> OutPoint {
+        OutPoint {
+            txid: txid.to_vec(),
+            vout,
+        }
+    }
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+    pub fn get_vout(&self) -> usize {
+        self.vout
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", HEXLOWER.encode(self.txid.as_slice()), self.vout)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (txid_hex, vout) = s
+            .split_once(':')
+            .ok_or_else(|| format!("outpoint {} is not in txid:vout form", s))?;
+        let txid = HEXLOWER_PERMISSIVE
+            .decode(txid_hex.as_bytes())
+            .map_err(|e| format!("invalid txid {}: {}", txid_hex, e))?;
+        let vout = vout
+            .parse()
+            .map_err(|e| format!("invalid output index {}: {}", vout, e))?;
+        Ok(OutPoint { txid, vout })
+    }
+}
+
+// An entry of the UTXO set. The output keeps its index in the creating
+// transaction, so spent siblings never shift it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UnspentOutput {
+    txid: Vec<u8>,
+    vout: usize,
+    output: TXOutput,
+    // height of the block that created the output
+    height: usize,
+    coinbase: bool,
+}
+
+impl UnspentOutput {
+    // the spendable outputs of `tx`; data outputs are left out
+    pub fn from_transaction(tx: &Transaction, height: usize) -> Vec<UnspentOutput> {
+        tx.get_vout()
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| !output.is_unspendable())
+            .map(|(vout, output)| UnspentOutput {
+                txid: tx.get_id().to_vec(),
+                vout,
+                output: output.clone(),
+                height,
+                coinbase: tx.is_coinbase(),
+            })
+            .collect()
+    }
+    pub fn get_outpoint(&self) -> OutPoint {
+        OutPoint::new(self.txid.as_slice(), self.vout)
+    }
+    pub fn get_output(&self) -> &TXOutput {
+        &self.output
+    }
+    pub fn get_value(&self) -> i32 {
+        self.output.get_value()
+    }
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+    pub fn is_coinbase(&self) -> bool {
+        self.coinbase
+    }
+    // whether a block at `height` may spend the output
+    pub fn is_mature(&self, height: usize, maturity: usize) -> bool {
+        !self.coinbase || height >= self.height + maturity
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use data_encoding::HEXLOWER;
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::transaction::{TXOutput, Transaction};
+use crate::wallet::{convert_address, ADDRESS_CHECK_SUM_LEN};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    Receive,
+    Send,
+    // coinbase reward paid to one of our addresses
+    Generate,
+    // every output goes back to the wallet, only the fee leaves it
+    SelfTransfer,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Category::Receive => "receive",
+            Category::Send => "send",
+            Category::Generate => "generate",
+            Category::SelfTransfer => "self",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LedgerEntry {
+    txid: String,
+    category: Category,
+    // value received, or value paid to others for sends
+    amount: i32,
+    // only known for transactions the wallet funded
+    fee: Option<i32>,
+    // our addresses involved in the transaction
+    addresses: Vec<String>,
+    counterparties: Vec<String>,
+    height: usize,
+    confirmations: usize,
+    timestamp: i64,
+}
+
+impl LedgerEntry {
+    pub fn get_txid(&self) -> &str {
+        self.txid.as_str()
+    }
+    pub fn get_category(&self) -> Category {
+        self.category
+    }
+    pub fn get_amount(&self) -> i32 {
+        self.amount
+    }
+    pub fn get_fee(&self) -> Option<i32> {
+        self.fee
+    }
+    pub fn get_addresses(&self) -> &[String] {
+        self.addresses.as_slice()
+    }
+    pub fn get_counterparties(&self) -> &[String] {
+        self.counterparties.as_slice()
+    }
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+    pub fn get_confirmations(&self) -> usize {
+        self.confirmations
+    }
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+// Wallet transaction history, rebuilt from the chain for a set of addresses so
+// it always follows the current best chain.
+pub struct WalletLedger {
+    entries: Vec<LedgerEntry>,
+}
+
+impl WalletLedger {
+    pub fn scan(blockchain: &Blockchain, addresses: &[String]) -> WalletLedger {
+        let ours: HashSet<Vec<u8>> = addresses
+            .iter()
+            .map(|address| {
+                let payload = crate::utils::base58_decode(address.as_str());
+                payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN].to_vec()
+            })
+            .collect();
+
+        let mut blocks: Vec<Block> = vec![];
+        let mut iterator = blockchain.iterator();
+        while let Some(block) = iterator.next() {
+            blocks.push(block);
+        }
+        blocks.reverse();
+
+        let best_height = blocks.last().map(|block| block.get_height()).unwrap_or(0);
+        let mut outputs: HashMap<Vec<u8>, Vec<TXOutput>> = HashMap::new();
+        let mut entries = vec![];
+        for block in &blocks {
+            for tx in block.get_transactions() {
+                outputs.insert(tx.get_id().to_vec(), tx.get_vout().to_vec());
+            }
+            for tx in block.get_transactions() {
+                if let Some(mut entry) = Self::entry_for(tx, &ours, &outputs) {
+                    entry.height = block.get_height();
+                    entry.confirmations = best_height - block.get_height() + 1;
+                    entry.timestamp = *block.get_timestamp();
+                    entries.push(entry);
+                }
+            }
+        }
+        WalletLedger { entries }
+    }
+
+    fn entry_for(
+        tx: &Transaction,
+        ours: &HashSet<Vec<u8>>,
+        outputs: &HashMap<Vec<u8>, Vec<TXOutput>>,
+    ) -> Option<LedgerEntry> {
+        let mut addresses: Vec<String> = vec![];
+        let mut counterparties: Vec<String> = vec![];
+        let add = |list: &mut Vec<String>, pub_key_hash: &[u8]| {
+            let address = convert_address(pub_key_hash);
+            if !list.contains(&address) {
+                list.push(address);
+            }
+        };
+
+        let mut input_value = 0;
+        let mut sent = 0;
+        if !tx.is_coinbase() {
+            for input in tx.get_vin() {
+                let prev_output = match outputs
+                    .get(input.get_txid())
+                    .and_then(|outs| outs.get(input.get_vout()))
+                {
+                    Some(prev_output) if prev_output.is_native() => prev_output,
+                    _ => continue,
+                };
+                let pub_key_hash = prev_output.get_pub_key_hash();
+                let value = prev_output.get_value();
+                input_value += value;
+                if ours.contains(&pub_key_hash) {
+                    sent += value;
+                    add(&mut addresses, pub_key_hash.as_slice());
+                } else {
+                    add(&mut counterparties, pub_key_hash.as_slice());
+                }
+            }
+        }
+
+        let mut received = 0;
+        let mut paid_out = 0;
+        // the ledger tracks native coins, assets are listed by getbalance
+        for output in tx.get_vout().iter().filter(|out| out.is_native()) {
+            let pub_key_hash = output.get_pub_key_hash();
+            if ours.contains(&pub_key_hash) {
+                received += output.get_value();
+                add(&mut addresses, pub_key_hash.as_slice());
+            } else {
+                paid_out += output.get_value();
+                if sent > 0 {
+                    add(&mut counterparties, pub_key_hash.as_slice());
+                }
+            }
+        }
+
+        let (category, amount, fee) = if sent > 0 {
+            let fee = Some((input_value as i64 - tx.output_value()) as i32);
+            if paid_out == 0 {
+                (Category::SelfTransfer, 0, fee)
+            } else {
+                (Category::Send, paid_out, fee)
+            }
+        } else if received > 0 {
+            if tx.is_coinbase() {
+                (Category::Generate, received, None)
+            } else {
+                (Category::Receive, received, None)
+            }
+        } else {
+            return None;
+        };
+
+        Some(LedgerEntry {
+            txid: HEXLOWER.encode(tx.get_id()),
+            category,
+            amount,
+            fee,
+            addresses,
+            counterparties,
+            height: 0,
+            confirmations: 0,
+            timestamp: 0,
+        })
+    }
+
+    // oldest first
+    pub fn get_entries(&self) -> &[LedgerEntry] {
+        self.entries.as_slice()
+    }
+}
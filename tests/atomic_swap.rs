@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Every chain lives in the working directory of the node, so each test chain
+// gets a directory of its own, with one wallet holding both parties' keys.
+struct Chain {
+    dir: PathBuf,
+}
+
+impl Chain {
+    fn new(name: &str) -> Chain {
+        let dir = std::env::temp_dir().join(format!("swap-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Chain { dir }
+    }
+
+    fn command(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_custom-blockchain"))
+            .args(args)
+            .current_dir(&self.dir)
+            .env("RUST_BACKTRACE", "0")
+            // rewards are spendable in the next block, as the tests fund
+            // every swap from freshly mined coins
+            .env("COINBASE_MATURITY", "1")
+            .output()
+            .unwrap()
+    }
+
+    fn run(&self, args: &[&str]) -> String {
+        let output = self.command(args);
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn fails(&self, args: &[&str]) -> String {
+        let output = self.command(args);
+        assert!(!output.status.success(), "{:?} should fail", args);
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    fn create_wallet(&self) -> String {
+        let output = self.run(&["createwallet"]);
+        field(&output, "Your new address")
+    }
+
+    fn balance(&self, address: &str) -> String {
+        field(
+            &self.run(&["getbalance", address]),
+            &format!("Balance of {}", address),
+        )
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// the value of a "name: value" line
+fn field(output: &str, name: &str) -> String {
+    let prefix = format!("{}: ", name);
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix(prefix.as_str()))
+        .unwrap_or_else(|| panic!("no {} in {}", name, output))
+        .trim()
+        .to_string()
+}
+
+#[test]
+fn swap_between_two_chains() {
+    let chain_a = Chain::new("a");
+    let chain_b = Chain::new("b");
+    let alice_a = chain_a.create_wallet();
+    let bob_a = chain_a.create_wallet();
+    let alice_b = chain_b.create_wallet();
+    let bob_b = chain_b.create_wallet();
+    chain_a.run(&["createblockchain", &alice_a]);
+    chain_b.run(&["createblockchain", &bob_b]);
+
+    // Alice locks 5 on chain A for Bob behind a secret only she knows
+    let initiated = chain_a.run(&[
+        "initiateswap",
+        &alice_a,
+        &bob_a,
+        "5",
+        "--timeout",
+        "10",
+        "--fee",
+        "1",
+        "--mine",
+    ]);
+    let secret = field(&initiated, "Secret");
+    let secret_hash = field(&initiated, "Secret hash");
+    let contract_a = field(&initiated, "Contract output");
+
+    // Bob checks Alice's contract and locks 5 on chain B for Alice with the same
+    // hash and a shorter timeout
+    let audit = chain_a.run(&["auditswap", &contract_a]);
+    assert_eq!(field(&audit, "Recipient"), bob_a);
+    assert_eq!(field(&audit, "Value"), "5");
+    assert_eq!(field(&audit, "Status"), "unspent");
+    let participated = chain_b.run(&[
+        "initiateswap",
+        &bob_b,
+        &alice_b,
+        "5",
+        "--secret-hash",
+        &secret_hash,
+        "--timeout",
+        "5",
+        "--fee",
+        "1",
+        "--mine",
+    ]);
+    assert!(!participated.contains("Secret: "));
+    let contract_b = field(&participated, "Contract output");
+
+    // a wrong secret doesn't unlock the contract
+    let wrong_secret = "00".repeat(32);
+    chain_b.fails(&["redeemswap", &contract_b, &wrong_secret, "--mine"]);
+
+    // Alice redeems on chain B, revealing the secret to Bob
+    chain_b.run(&["redeemswap", &contract_b, &secret, "--fee", "1", "--mine"]);
+    let audit = chain_b.run(&["auditswap", &contract_b]);
+    assert!(field(&audit, "Status").starts_with("redeemed"));
+    let revealed = field(&audit, "Secret");
+    assert_eq!(revealed, secret);
+
+    // Bob redeems on chain A with it
+    chain_a.run(&["redeemswap", &contract_a, &revealed, "--fee", "1", "--mine"]);
+    assert!(field(&chain_a.run(&["auditswap", &contract_a]), "Status").starts_with("redeemed"));
+
+    // 5 less the fee, plus the reward of the block each redeem was mined in
+    assert_eq!(chain_a.balance(&bob_a), "14");
+    assert_eq!(chain_b.balance(&alice_b), "14");
+}
+
+#[test]
+fn refund_after_timeout() {
+    let chain = Chain::new("refund");
+    let alice = chain.create_wallet();
+    let bob = chain.create_wallet();
+    chain.run(&["createblockchain", &alice]);
+
+    // locked at height 1 until height 3
+    let initiated = chain.run(&[
+        "initiateswap",
+        &alice,
+        &bob,
+        "5",
+        "--timeout",
+        "3",
+        "--fee",
+        "1",
+        "--mine",
+    ]);
+    let contract = field(&initiated, "Contract output");
+    assert_eq!(field(&initiated, "Refundable from"), "height 3");
+
+    // the next block is at height 2, too early
+    let error = chain.fails(&["refundswap", &contract, "--mine"]);
+    assert!(error.contains("can't be refunded before height 3"));
+
+    chain.run(&["send", &alice, &alice, "1", "1"]);
+    chain.run(&["refundswap", &contract, "--fee", "1", "--mine"]);
+    let audit = chain.run(&["auditswap", &contract]);
+    assert!(field(&audit, "Status").starts_with("refunded"));
+    assert!(!audit.contains("Secret: "));
+}